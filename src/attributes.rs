@@ -0,0 +1,414 @@
+//! Storage of hash values in extended attributes, so that they travel with files that are
+//! copied or backed up with their attributes.
+//!
+//! Each hash value is stored in lowercase hex in `user.checksum.<algorithm>`, where the
+//! algorithm is the lowercase `--tag` label with `/` replaced by `-`, e.g.
+//! `user.checksum.sha256`, `user.checksum.sha512-256` or `user.checksum.blake2b-256`. The
+//! modification time of the file when it was hashed is stored alongside in
+//! `user.checksum.mtime`, as seconds and nanoseconds since the Unix epoch, e.g.
+//! `1709213825.123456789`.
+//!
+//! A hash value whose file has been modified since is stale rather than wrong: the file was
+//! presumably changed on purpose. A hash value that doesn't match although the modification
+//! time is unchanged points to corruption.
+
+use crate::blake2::Blake2Params;
+use crate::check::{plural, warn_mismatched, warn_unreadable};
+use crate::encoding::to_hex_lowercase;
+use crate::input::IoOptions;
+use crate::{CheckedFile, DigestType, STDIN_PATH, perform_hash};
+use camino::Utf8Path;
+use clap::ValueEnum;
+use std::fs::Metadata;
+use std::io;
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+/// The prefix of the names of all attributes written by this program.
+const ATTRIBUTE_PREFIX: &str = "user.checksum.";
+
+/// The attribute holding the modification time of a file when its hash values were stored.
+const MTIME_ATTRIBUTE: &str = "user.checksum.mtime";
+
+/// Returns the name of the attribute holding the hash value of `digest`.
+fn attribute_name(digest: &DigestType, blake2: &Blake2Params) -> String {
+    format!(
+        "{}{}",
+        ATTRIBUTE_PREFIX,
+        digest.label(blake2).to_lowercase().replace('/', "-")
+    )
+}
+
+/// Parses the name of an attribute as returned by [`attribute_name`] into the algorithm and
+/// BLAKE2 output length.
+fn parse_attribute_name(name: &str) -> Option<(DigestType, Blake2Params)> {
+    let algorithm = name.strip_prefix(ATTRIBUTE_PREFIX)?;
+    DigestType::value_variants().iter().find_map(|digest| {
+        let default = Blake2Params::default();
+        if attribute_name(digest, &default)[ATTRIBUTE_PREFIX.len()..] == *algorithm {
+            return Some((digest.clone(), default));
+        }
+        // Only BLAKE2 labels carry a length, e.g. `blake2b-256`.
+        let bits = algorithm
+            .strip_prefix(digest.name().to_lowercase().as_str())?
+            .strip_prefix('-')?;
+        let (digest, length) = Blake2Params::parse_label(&format!("{}-{}", digest.name(), bits))?;
+        Some((
+            digest,
+            Blake2Params {
+                length,
+                ..Blake2Params::default()
+            },
+        ))
+    })
+}
+
+/// Returns the modification time of a file in the form stored in [`MTIME_ATTRIBUTE`].
+fn mtime_stamp(metadata: &Metadata) -> io::Result<String> {
+    let modified = metadata.modified()?;
+    Ok(match modified.duration_since(UNIX_EPOCH) {
+        Ok(since) => format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            format!("-{}.{:09}", before.as_secs(), before.subsec_nanos())
+        }
+    })
+}
+
+/// Stores `hash_values`, calculated for each of `digests`, in the extended attributes of
+/// `path`, along with its modification time from `metadata`, which must have been read before
+/// the file was hashed.
+///
+/// Attributes of other algorithms that were stored for an earlier modification time are
+/// removed, as they no longer describe the contents.
+///
+/// # Errors
+///
+/// Returns an error if `path` is standard input, was modified while it was hashed, or its
+/// attributes can't be written, e.g. because its filesystem doesn't support them.
+pub fn store(
+    path: &Utf8Path,
+    metadata: Option<&Metadata>,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    hash_values: &[Vec<u8>],
+) -> io::Result<()> {
+    let Some(metadata) = metadata.filter(|_| path != STDIN_PATH) else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "standard input has no extended attributes",
+        ));
+    };
+    let stamp = mtime_stamp(metadata)?;
+    if mtime_stamp(&std::fs::metadata(path)?)? != stamp {
+        return Err(io::Error::other(
+            "the file was modified while it was hashed",
+        ));
+    }
+
+    let names: Vec<String> = digests
+        .iter()
+        .map(|digest| attribute_name(digest, blake2))
+        .collect();
+    if xattr::get_deref(path, MTIME_ATTRIBUTE)?.as_deref() != Some(stamp.as_bytes()) {
+        for name in xattr::list_deref(path)? {
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with(ATTRIBUTE_PREFIX)
+                && name != MTIME_ATTRIBUTE
+                && !names.iter().any(|new_name| new_name == name)
+            {
+                xattr::remove_deref(path, name)?;
+            }
+        }
+    }
+    for (name, hash_value) in names.iter().zip(hash_values) {
+        xattr::set_deref(path, name, to_hex_lowercase(hash_value).as_bytes())?;
+    }
+    // The stamp is written last, so that hash values which were only partly written are stale
+    // rather than mismatching.
+    xattr::set_deref(path, MTIME_ATTRIBUTE, stamp.as_bytes())
+}
+
+/// The result of verifying the stored hash values of a file.
+#[derive(Debug, PartialEq)]
+enum Verification {
+    /// Every stored hash value matched.
+    Ok,
+    /// A stored hash value didn't match although the file wasn't modified since.
+    Mismatched,
+    /// The file was modified after the hash values were stored.
+    Stale,
+    /// No hash value of the requested algorithms is stored.
+    Missing,
+    /// The file or its attributes couldn't be read.
+    Unreadable,
+}
+
+/// Counts of the problems found by [`verify_files`].
+#[derive(Debug, Default)]
+struct VerifySummary {
+    mismatched: usize,
+    stale: usize,
+    missing: usize,
+    unreadable: usize,
+}
+
+/// Checks each of `files` against the hash values stored in its extended attributes and
+/// prints `OK`, `FAILED`, `STALE` or `MISSING` for it.
+///
+/// Only the hash values of `digests` are checked, or, if it is empty, every stored hash value of
+/// an algorithm that isn't insecure.
+///
+/// Returns [`ExitCode::FAILURE`] if a hash value did not match or a file could not be read.
+/// Stale and missing attributes are reported but don't fail.
+pub fn verify_files(
+    files: &[CheckedFile],
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+) -> ExitCode {
+    let mut summary = VerifySummary::default();
+    for file in files {
+        let path = &file.file_path;
+        if let Err(err) = &file.hashable {
+            eprintln!("{}: unable to hash this file", err);
+            println!("{}: FAILED open or read", path);
+            summary.unreadable += 1;
+            continue;
+        }
+        match verify_file(path, digests, blake2, io_options) {
+            Verification::Ok => println!("{}: OK", path),
+            Verification::Mismatched => {
+                println!("{}: FAILED", path);
+                summary.mismatched += 1;
+            }
+            Verification::Stale => {
+                println!("{}: STALE", path);
+                summary.stale += 1;
+            }
+            Verification::Missing => {
+                println!("{}: MISSING", path);
+                summary.missing += 1;
+            }
+            Verification::Unreadable => {
+                println!("{}: FAILED open or read", path);
+                summary.unreadable += 1;
+            }
+        }
+    }
+    print_summary(&summary);
+    if summary.mismatched > 0 || summary.unreadable > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn verify_file(
+    path: &Utf8Path,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+) -> Verification {
+    if path == STDIN_PATH {
+        eprintln!("{}: standard input has no extended attributes", path);
+        return Verification::Missing;
+    }
+    let stored = match read_attributes(path) {
+        Ok(stored) => stored,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Verification::Unreadable;
+        }
+    };
+    let selected: Vec<(DigestType, Blake2Params, String)> = stored
+        .into_iter()
+        .filter(|(digest, params, _)| {
+            if digests.is_empty() {
+                !digest.is_insecure()
+            } else {
+                digests.contains(digest) && digest.label(params) == digest.label(blake2)
+            }
+        })
+        .collect();
+    if selected.is_empty() {
+        return Verification::Missing;
+    }
+
+    let current_stamp = match std::fs::metadata(path).and_then(|metadata| mtime_stamp(&metadata)) {
+        Ok(stamp) => stamp,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Verification::Unreadable;
+        }
+    };
+    match xattr::get_deref(path, MTIME_ATTRIBUTE) {
+        Ok(Some(stamp)) if stamp == current_stamp.as_bytes() => {}
+        Ok(_) => return Verification::Stale,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Verification::Unreadable;
+        }
+    }
+
+    // BLAKE2 hash values of different lengths need separate parameters, so the file is read
+    // once per distinct set of them.
+    let mut groups: Vec<(&Blake2Params, Vec<DigestType>, Vec<&str>)> = Vec::new();
+    for (digest, params, hex) in &selected {
+        match groups
+            .iter_mut()
+            .find(|(group_params, _, _)| *group_params == params)
+        {
+            Some((_, group_digests, group_hexes)) => {
+                group_digests.push(digest.clone());
+                group_hexes.push(hex);
+            }
+            None => groups.push((params, vec![digest.clone()], vec![hex])),
+        }
+    }
+    // A cache can't see changes that kept the modification time, which are exactly the ones
+    // this is meant to find.
+    let io_options = IoOptions {
+        cache: None,
+        ..*io_options
+    };
+    let mut verification = Verification::Ok;
+    for (params, group_digests, hexes) in groups {
+        match perform_hash(&path.to_path_buf(), &group_digests, params, &io_options) {
+            Ok(hash_values) => {
+                if hash_values
+                    .iter()
+                    .zip(hexes)
+                    .any(|(hash_value, hex)| to_hex_lowercase(hash_value) != hex)
+                {
+                    verification = Verification::Mismatched;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: error during hashing: {}", path, e);
+                return Verification::Unreadable;
+            }
+        }
+    }
+    verification
+}
+
+/// Reads the hash values stored in the attributes of `path`, skipping those of algorithms that
+/// aren't supported by this build.
+///
+/// A filesystem without extended attributes has none stored.
+fn read_attributes(path: &Utf8Path) -> io::Result<Vec<(DigestType, Blake2Params, String)>> {
+    let names = match xattr::list_deref(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut stored = Vec::new();
+    for name in names {
+        let Some((digest, params)) = name.to_str().and_then(parse_attribute_name) else {
+            continue;
+        };
+        if let Some(value) = xattr::get_deref(path, &name)? {
+            stored.push((digest, params, String::from_utf8_lossy(&value).into_owned()));
+        }
+    }
+    stored.sort_by_key(|(digest, params, _)| attribute_name(digest, params));
+    Ok(stored)
+}
+
+fn print_summary(summary: &VerifySummary) {
+    if summary.missing > 0 {
+        eprintln!(
+            "WARNING: {} {} no checksum attribute",
+            summary.missing,
+            plural(summary.missing, "file has", "files have")
+        );
+    }
+    if summary.stale > 0 {
+        eprintln!(
+            "WARNING: {} {} modified since its checksum attribute was stored",
+            summary.stale,
+            plural(summary.stale, "file was", "files were")
+        );
+    }
+    warn_unreadable(summary.unreadable);
+    warn_mismatched(summary.mismatched);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_names_round_trip() {
+        let blake2_256 = Blake2Params {
+            length: Some(32),
+            ..Blake2Params::default()
+        };
+        for (digest, params, name) in [
+            (
+                DigestType::SHA256,
+                Blake2Params::default(),
+                "user.checksum.sha256",
+            ),
+            (
+                DigestType::SHA512_256,
+                Blake2Params::default(),
+                "user.checksum.sha512-256",
+            ),
+            (
+                DigestType::SHA3_256,
+                Blake2Params::default(),
+                "user.checksum.sha3-256",
+            ),
+            (
+                DigestType::BLAKE2b,
+                Blake2Params::default(),
+                "user.checksum.blake2b",
+            ),
+            (DigestType::BLAKE2b, blake2_256, "user.checksum.blake2b-256"),
+            (
+                DigestType::BLAKE3,
+                Blake2Params::default(),
+                "user.checksum.blake3",
+            ),
+        ] {
+            assert_eq!(attribute_name(&digest, &params), name);
+            assert_eq!(
+                parse_attribute_name(name),
+                Some((digest, params)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_other_attributes() {
+        for name in [
+            MTIME_ATTRIBUTE,
+            "user.checksum.",
+            "user.checksum.sha256-256",
+            "user.checksum.blake2b-255",
+            "user.checksum.SHA256",
+            "user.shatag.sha256",
+            "sha256",
+        ] {
+            assert_eq!(parse_attribute_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn formats_mtime_stamps() {
+        let file = std::env::temp_dir().join(format!("digest-mtime-{}", std::process::id()));
+        let modified = UNIX_EPOCH + std::time::Duration::new(1_709_213_825, 123_456_789);
+        std::fs::File::create(&file)
+            .and_then(|f| f.set_modified(modified))
+            .unwrap();
+        let metadata = std::fs::metadata(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(mtime_stamp(&metadata).unwrap(), "1709213825.123456789");
+    }
+}
@@ -0,0 +1,288 @@
+//! The BLAKE2 family, whose output length, key, salt and personalization are chosen at run time.
+
+use crate::{DigestType, Hasher};
+use std::str::FromStr;
+
+/// Optional BLAKE2 parameters as given on the command line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Blake2Params {
+    /// The output length in bytes, or `None` for the maximum of the chosen variant.
+    pub length: Option<usize>,
+    /// The key for keyed hashing (MAC mode); empty for unkeyed hashing.
+    pub key: Vec<u8>,
+    /// The salt, padded with zeros to the variant's salt size.
+    pub salt: Vec<u8>,
+    /// The personalization string, padded with zeros to the variant's personalization size.
+    pub personal: Vec<u8>,
+}
+
+/// The maximum sizes supported by a BLAKE2 variant, in bytes.
+struct Limits {
+    output: usize,
+    key: usize,
+    salt: usize,
+    personal: usize,
+}
+
+const BLAKE2B_LIMITS: Limits = Limits {
+    output: blake2b_simd::OUTBYTES,
+    key: blake2b_simd::KEYBYTES,
+    salt: blake2b_simd::SALTBYTES,
+    personal: blake2b_simd::PERSONALBYTES,
+};
+
+const BLAKE2S_LIMITS: Limits = Limits {
+    output: blake2s_simd::OUTBYTES,
+    key: blake2s_simd::KEYBYTES,
+    salt: blake2s_simd::SALTBYTES,
+    personal: blake2s_simd::PERSONALBYTES,
+};
+
+/// Returns the limits of `digest`, or `None` if it is not a BLAKE2 variant.
+fn limits(digest: &DigestType) -> Option<&'static Limits> {
+    match digest {
+        DigestType::BLAKE2b => Some(&BLAKE2B_LIMITS),
+        DigestType::BLAKE2s => Some(&BLAKE2S_LIMITS),
+        _ => None,
+    }
+}
+
+impl Blake2Params {
+    /// Returns true if any parameter differs from its default.
+    fn is_set(&self) -> bool {
+        *self != Blake2Params::default()
+    }
+
+    /// Returns true if a key, salt or personalization is set, so that hash values depend on more
+    /// than the variant and output length.
+    pub fn is_keyed(&self) -> bool {
+        !self.key.is_empty() || !self.salt.is_empty() || !self.personal.is_empty()
+    }
+
+    /// Checks that the parameters can be used with each BLAKE2 variant in `digests`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a parameter is set but `digests` contains no BLAKE2 variant, or if it
+    /// exceeds what one of the variants supports.
+    pub fn validate(&self, digests: &[DigestType]) -> Result<(), String> {
+        if self.is_set() && !digests.iter().any(|digest| limits(digest).is_some()) {
+            return Err(String::from(
+                "--length, --key, --salt and --personal require blake2b or blake2s",
+            ));
+        }
+        for digest in digests {
+            if let Some(limits) = limits(digest) {
+                self.validate_limits(digest, limits)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_limits(&self, digest: &DigestType, limits: &Limits) -> Result<(), String> {
+        if let Some(length) = self.length
+            && (length == 0 || length > limits.output)
+        {
+            return Err(format!(
+                "{} supports lengths from 8 to {} bits",
+                digest.name(),
+                limits.output * 8
+            ));
+        }
+        for (param, value, max) in [
+            ("key", &self.key, limits.key),
+            ("salt", &self.salt, limits.salt),
+            ("personalization", &self.personal, limits.personal),
+        ] {
+            if value.len() > max {
+                return Err(format!(
+                    "{} supports a {} of at most {} bytes",
+                    digest.name(),
+                    param,
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size of a hash value calculated by `digest` with these parameters, in bytes.
+    pub fn output_size(&self, digest: &DigestType) -> Option<usize> {
+        limits(digest).map(|limits| self.length.unwrap_or(limits.output))
+    }
+
+    /// Returns the label printed by `--tag`, which includes the length in bits if it isn't the
+    /// maximum, e.g. `BLAKE2b-256`.
+    pub fn label(&self, digest: &DigestType) -> Option<String> {
+        let limits = limits(digest)?;
+        match self.length {
+            Some(length) if length != limits.output => {
+                Some(format!("{}-{}", digest.name(), length * 8))
+            }
+            _ => Some(digest.name().to_string()),
+        }
+    }
+
+    /// Parses a label as returned by [`Blake2Params::label`] into the variant and output length
+    /// in bytes.
+    pub fn parse_label(label: &str) -> Option<(DigestType, Option<usize>)> {
+        let (name, bits) = match label.split_once('-') {
+            Some((name, bits)) => (name, Some(bits.parse::<usize>().ok()?)),
+            None => (label, None),
+        };
+        let digest = DigestType::from_name(name)?;
+        limits(&digest)?;
+        match bits {
+            Some(bits) if bits.is_multiple_of(8) => Some((digest, Some(bits / 8))),
+            Some(_) => None,
+            None => Some((digest, None)),
+        }
+    }
+}
+
+/// Creates a [`Hasher`] for BLAKE2b or BLAKE2s.
+///
+/// `params` must have been checked with [`Blake2Params::validate`].
+pub fn new_hasher(digest: &DigestType, params: &Blake2Params) -> Box<dyn Hasher> {
+    match digest {
+        DigestType::BLAKE2b => Box::new(Blake2bHasher(
+            blake2b_simd::Params::new()
+                .hash_length(params.length.unwrap_or(blake2b_simd::OUTBYTES))
+                .key(&params.key)
+                .salt(&params.salt)
+                .personal(&params.personal)
+                .to_state(),
+        )),
+        DigestType::BLAKE2s => Box::new(Blake2sHasher(
+            blake2s_simd::Params::new()
+                .hash_length(params.length.unwrap_or(blake2s_simd::OUTBYTES))
+                .key(&params.key)
+                .salt(&params.salt)
+                .personal(&params.personal)
+                .to_state(),
+        )),
+        _ => unreachable!("{} is not a BLAKE2 variant", digest.name()),
+    }
+}
+
+struct Blake2bHasher(blake2b_simd::State);
+
+impl Hasher for Blake2bHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+struct Blake2sHasher(blake2s_simd::State);
+
+impl Hasher for Blake2sHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+/// Bytes given in hexadecimal on the command line.
+#[derive(Debug, Clone)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl FromStr for HexBytes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("{:?} is not a hexadecimal string", s));
+        }
+        if !s.len().is_multiple_of(2) {
+            return Err(String::from(
+                "expected an even number of hexadecimal digits",
+            ));
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("checked for hex digits above"))
+            .collect();
+        Ok(HexBytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::to_hex_lowercase;
+
+    fn hash(digest: &DigestType, params: &Blake2Params, data: &[u8]) -> String {
+        let mut hasher = new_hasher(digest, params);
+        hasher.update(data);
+        to_hex_lowercase(&hasher.finish())
+    }
+
+    #[test]
+    fn known_answers() {
+        // RFC 7693, appendices A and B.
+        assert_eq!(
+            hash(&DigestType::BLAKE2b, &Blake2Params::default(), b"abc"),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+        assert_eq!(
+            hash(&DigestType::BLAKE2s, &Blake2Params::default(), b"abc"),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+    }
+
+    #[test]
+    fn parses_hex_bytes() {
+        assert_eq!("".parse::<HexBytes>().unwrap().0, Vec::<u8>::new());
+        assert_eq!("00fFa0".parse::<HexBytes>().unwrap().0, [0x00, 0xff, 0xa0]);
+        assert!("abc".parse::<HexBytes>().is_err());
+        assert!("0g".parse::<HexBytes>().is_err());
+        assert!("+1".parse::<HexBytes>().is_err());
+    }
+
+    #[test]
+    fn labels_round_trip() {
+        let params = Blake2Params {
+            length: Some(32),
+            ..Blake2Params::default()
+        };
+        assert_eq!(params.label(&DigestType::BLAKE2b).unwrap(), "BLAKE2b-256");
+        assert_eq!(
+            Blake2Params::parse_label("BLAKE2b-256"),
+            Some((DigestType::BLAKE2b, Some(32)))
+        );
+        // The maximum length is the default, so it isn't part of the label.
+        let params = Blake2Params {
+            length: Some(64),
+            ..Blake2Params::default()
+        };
+        assert_eq!(params.label(&DigestType::BLAKE2b).unwrap(), "BLAKE2b");
+        assert_eq!(Blake2Params::default().label(&DigestType::SHA256), None);
+        assert_eq!(Blake2Params::parse_label("BLAKE2b-255"), None);
+        assert_eq!(Blake2Params::parse_label("SHA512-256"), None);
+    }
+
+    #[test]
+    fn validates_limits() {
+        let too_long = Blake2Params {
+            length: Some(33),
+            ..Blake2Params::default()
+        };
+        assert!(too_long.validate(&[DigestType::BLAKE2b]).is_ok());
+        assert!(too_long.validate(&[DigestType::BLAKE2s]).is_err());
+        let salted = Blake2Params {
+            salt: vec![0; 9],
+            ..Blake2Params::default()
+        };
+        assert!(salted.validate(&[DigestType::BLAKE2b]).is_ok());
+        assert!(salted.validate(&[DigestType::BLAKE2s]).is_err());
+        assert!(salted.validate(&[DigestType::SHA256]).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+//! A persistent cache of hash values, so that files which haven't changed since they were last
+//! hashed aren't read again.
+//!
+//! Entries are keyed by the device and inode number, size and modification time of a file,
+//! plus the algorithm. A file that is modified without changing its size or modification time,
+//! e.g. by corruption on disk or by a tool that restores timestamps, is therefore not noticed
+//! until `--refresh-cache` is given.
+//!
+//! The cache is a text file of the form
+//!
+//! ```text
+//! digest-cache-v1
+//! <device> TAB <inode> TAB <size> TAB <mtime in ns> TAB <algorithm> TAB <hex> TAB <path>
+//! ```
+//!
+//! where backslashes and newlines in paths are escaped as `\\` and `\n`. It is read completely
+//! when the program starts and replaced atomically when it ends, so concurrent runs don't
+//! corrupt it, but only the entries of the last one to finish are kept.
+
+use crate::blake2::Blake2Params;
+use crate::check::unescape_path;
+use crate::encoding::to_hex_lowercase;
+use crate::{DigestType, STDIN_PATH};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::sync::Mutex;
+
+/// The first line of a cache file, identifying its version.
+const CACHE_VERSION: &str = "digest-cache-v1";
+
+/// The identity and state of a file, which changes whenever its contents do.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FileState {
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime_ns: i128,
+}
+
+impl FileState {
+    /// Returns the state of the regular file at `path`, or `None` if it isn't a regular file
+    /// or its state can't be determined on this platform.
+    fn of(path: &Utf8Path) -> Option<FileState> {
+        if path == STDIN_PATH {
+            return None;
+        }
+        let metadata = std::fs::metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        FileState::from_metadata(&metadata)
+    }
+
+    #[cfg(unix)]
+    fn from_metadata(metadata: &std::fs::Metadata) -> Option<FileState> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileState {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            size: metadata.size(),
+            mtime_ns: i128::from(metadata.mtime()) * 1_000_000_000
+                + i128::from(metadata.mtime_nsec()),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn from_metadata(_metadata: &std::fs::Metadata) -> Option<FileState> {
+        None
+    }
+}
+
+/// A cached hash value, with the path it was calculated for so the entry can be pruned once
+/// that file changes or is removed.
+#[derive(Debug)]
+struct Entry {
+    hash_value: Vec<u8>,
+    path: Utf8PathBuf,
+}
+
+/// Cached hash values, keyed by file state and algorithm label, e.g. `BLAKE2b-256`.
+type Entries = HashMap<(FileState, String), Entry>;
+
+/// A cache file loaded into memory.
+#[derive(Debug)]
+pub struct Cache {
+    path: Utf8PathBuf,
+    /// Ignore cached hash values, but still store the ones calculated.
+    refresh: bool,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    /// Loads the cache file at `path`, or starts an empty cache if it doesn't exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but can't be read or isn't a cache file.
+    pub fn open(path: &Utf8Path, refresh: bool) -> io::Result<Cache> {
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                let mut lines = io::BufReader::new(file).lines();
+                if lines.next().transpose()?.as_deref() != Some(CACHE_VERSION) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: not a {} file", path, CACHE_VERSION),
+                    ));
+                }
+                for line in lines {
+                    // Lines that can't be parsed are dropped, as they will be rehashed anyway.
+                    if let Some((key, entry)) = parse_line(&line?) {
+                        entries.insert(key, entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Cache {
+            path: path.to_path_buf(),
+            refresh,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Returns the hash values of `path` for each of `digests`, taking those that are cached
+    /// and calculating the others with `hash`, which is called with the missing algorithms.
+    ///
+    /// `blake2` must not contain a key, salt or personalization, because those aren't part of
+    /// the algorithm label the entries are keyed by.
+    pub fn get_or_hash<F>(
+        &self,
+        path: &Utf8Path,
+        digests: &[DigestType],
+        blake2: &Blake2Params,
+        hash: F,
+    ) -> io::Result<Vec<Vec<u8>>>
+    where
+        F: FnOnce(&[DigestType]) -> io::Result<Vec<Vec<u8>>>,
+    {
+        let Some(state) = FileState::of(path) else {
+            return hash(digests);
+        };
+        let labels: Vec<String> = digests.iter().map(|digest| digest.label(blake2)).collect();
+        let mut hash_values: Vec<Option<Vec<u8>>> = if self.refresh {
+            vec![None; digests.len()]
+        } else {
+            let entries = self.entries.lock().expect("cache lock poisoned");
+            labels
+                .iter()
+                .map(|label| {
+                    entries
+                        .get(&(state.clone(), label.clone()))
+                        .map(|entry| entry.hash_value.clone())
+                })
+                .collect()
+        };
+
+        let missing: Vec<DigestType> = digests
+            .iter()
+            .zip(&hash_values)
+            .filter(|(_, hash_value)| hash_value.is_none())
+            .map(|(digest, _)| digest.clone())
+            .collect();
+        if !missing.is_empty() {
+            let mut calculated = hash(&missing)?.into_iter();
+            // A file modified while it was read may have been hashed partly before and partly
+            // after the change, so the result is only cached if the file stayed the same.
+            let unchanged = FileState::of(path).as_ref() == Some(&state);
+            // Pruning must find the file again, whatever the working directory.
+            let absolute_path = path
+                .canonicalize_utf8()
+                .unwrap_or_else(|_| path.to_path_buf());
+            let mut entries = self.entries.lock().expect("cache lock poisoned");
+            for (hash_value, label) in hash_values.iter_mut().zip(&labels) {
+                if hash_value.is_some() {
+                    continue;
+                }
+                let calculated = calculated.next().expect("one hash value per digest");
+                if unchanged {
+                    entries.insert(
+                        (state.clone(), label.clone()),
+                        Entry {
+                            hash_value: calculated.clone(),
+                            path: absolute_path.clone(),
+                        },
+                    );
+                }
+                *hash_value = Some(calculated);
+            }
+        }
+        Ok(hash_values.into_iter().flatten().collect())
+    }
+
+    /// Removes the entries of files that have been changed or removed since they were hashed.
+    ///
+    /// Returns the number of entries removed.
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let before = entries.len();
+        entries.retain(|(state, _), entry| FileState::of(&entry.path).as_ref() == Some(state));
+        before - entries.len()
+    }
+
+    /// Writes the cache back to its file, replacing it atomically.
+    pub fn save(&self) -> io::Result<()> {
+        self.replace_file()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.path, e)))
+    }
+
+    fn replace_file(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent()
+            && !parent.as_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = Utf8PathBuf::from(format!("{}.{}.tmp", self.path, std::process::id()));
+        let result = self.write(&temp_path);
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+            return result;
+        }
+        std::fs::rename(&temp_path, &self.path)
+    }
+
+    fn write(&self, path: &Utf8Path) -> io::Result<()> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", CACHE_VERSION)?;
+        for ((state, label), entry) in entries.iter() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                state.device,
+                state.inode,
+                state.size,
+                state.mtime_ns,
+                label,
+                to_hex_lowercase(&entry.hash_value),
+                entry
+                    .path
+                    .as_str()
+                    .replace('\\', "\\\\")
+                    .replace('\n', "\\n")
+            )?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+/// Parses one entry of a cache file.
+fn parse_line(line: &str) -> Option<((FileState, String), Entry)> {
+    let mut fields = line.splitn(7, '\t');
+    let state = FileState {
+        device: fields.next()?.parse().ok()?,
+        inode: fields.next()?.parse().ok()?,
+        size: fields.next()?.parse().ok()?,
+        mtime_ns: fields.next()?.parse().ok()?,
+    };
+    let label = fields.next()?.to_string();
+    let hash_value = data_encoding::HEXLOWER
+        .decode(fields.next()?.as_bytes())
+        .ok()?;
+    let path = Utf8PathBuf::from(unescape_path(fields.next()?)?);
+    Some(((state, label), Entry { hash_value, path }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test, removing what an earlier run left behind.
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("digest-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Looks `path` up in `cache`, returning the hash values and the algorithms that had to be
+    /// hashed. Each calculated hash value is the name of its algorithm.
+    fn lookup(
+        cache: &Cache,
+        path: &Utf8Path,
+        digests: &[DigestType],
+        blake2: &Blake2Params,
+    ) -> (Vec<Vec<u8>>, Vec<DigestType>) {
+        let mut hashed = Vec::new();
+        let hash_values = cache
+            .get_or_hash(path, digests, blake2, |missing| {
+                hashed = missing.to_vec();
+                Ok(missing
+                    .iter()
+                    .map(|digest| digest.name().as_bytes().to_vec())
+                    .collect())
+            })
+            .unwrap();
+        (hash_values, hashed)
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let dir = test_dir("write");
+        let cache_path = dir.join("cache");
+        let cache = Cache::open(&cache_path, false).unwrap();
+        let state = FileState {
+            device: 1,
+            inode: 2,
+            size: 3,
+            mtime_ns: -4,
+        };
+        let path = Utf8PathBuf::from("/a\\b\nc\td");
+        cache.entries.lock().unwrap().insert(
+            (state.clone(), "BLAKE2b-256".to_string()),
+            Entry {
+                hash_value: vec![0xab, 0xcd],
+                path: path.clone(),
+            },
+        );
+        cache.save().unwrap();
+
+        let contents = std::fs::read_to_string(&cache_path).unwrap();
+        assert_eq!(
+            contents,
+            "digest-cache-v1\n1\t2\t3\t-4\tBLAKE2b-256\tabcd\t/a\\\\b\\nc\td\n"
+        );
+        let (key, entry) = parse_line(contents.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(key, (state.clone(), "BLAKE2b-256".to_string()));
+        assert_eq!(entry.hash_value, [0xab, 0xcd]);
+        assert_eq!(entry.path, path);
+
+        let reopened = Cache::open(&cache_path, false).unwrap();
+        let entries = reopened.entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[&key].path, path);
+        drop(entries);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_invalid_lines() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("1\t2\t3\t4\tSHA256\tabcd").is_none());
+        assert!(parse_line("x\t2\t3\t4\tSHA256\tabcd\t/a").is_none());
+        assert!(parse_line("1\t2\t3\t4\tSHA256\tABCD\t/a").is_none());
+    }
+
+    #[test]
+    fn open_rejects_other_files() {
+        let dir = test_dir("reject");
+        let cache_path = dir.join("cache");
+        std::fs::write(&cache_path, "digest-cache-v2\n").unwrap();
+        let err = Cache::open(&cache_path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&cache_path, "").unwrap();
+        assert!(Cache::open(&cache_path, false).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hashes_only_missing_digests() {
+        let dir = test_dir("missing");
+        let file = dir.join("a");
+        std::fs::write(&file, "abc").unwrap();
+        let cache = Cache::open(&dir.join("cache"), false).unwrap();
+        let blake2 = Blake2Params::default();
+
+        let (_, hashed) = lookup(&cache, &file, &[DigestType::SHA256], &blake2);
+        assert_eq!(hashed, [DigestType::SHA256]);
+        let (hash_values, hashed) = lookup(
+            &cache,
+            &file,
+            &[DigestType::BLAKE3, DigestType::SHA256],
+            &blake2,
+        );
+        assert_eq!(hashed, [DigestType::BLAKE3]);
+        assert_eq!(hash_values, [b"BLAKE3".to_vec(), b"SHA256".to_vec()]);
+        let (_, hashed) = lookup(
+            &cache,
+            &file,
+            &[DigestType::SHA256, DigestType::BLAKE3],
+            &blake2,
+        );
+        assert!(hashed.is_empty());
+
+        // Modifying the file changes its state, so nothing is found.
+        std::fs::write(&file, "abcd").unwrap();
+        let (_, hashed) = lookup(&cache, &file, &[DigestType::SHA256], &blake2);
+        assert_eq!(hashed, [DigestType::SHA256]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refresh_never_returns_cached_values() {
+        let dir = test_dir("refresh");
+        let file = dir.join("a");
+        std::fs::write(&file, "abc").unwrap();
+        let cache_path = dir.join("cache");
+        let blake2 = Blake2Params::default();
+        let cache = Cache::open(&cache_path, false).unwrap();
+        lookup(&cache, &file, &[DigestType::SHA256], &blake2);
+        cache.save().unwrap();
+
+        let cache = Cache::open(&cache_path, true).unwrap();
+        for _ in 0..2 {
+            let (_, hashed) = lookup(&cache, &file, &[DigestType::SHA256], &blake2);
+            assert_eq!(hashed, [DigestType::SHA256]);
+        }
+        // The values calculated are still stored.
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keyed_by_blake2_length() {
+        let dir = test_dir("blake2");
+        let file = dir.join("a");
+        std::fs::write(&file, "abc").unwrap();
+        let cache = Cache::open(&dir.join("cache"), false).unwrap();
+        let blake2_256 = Blake2Params {
+            length: Some(32),
+            ..Blake2Params::default()
+        };
+
+        lookup(&cache, &file, &[DigestType::BLAKE2b], &blake2_256);
+        let (_, hashed) = lookup(&cache, &file, &[DigestType::BLAKE2b], &blake2_256);
+        assert!(hashed.is_empty());
+        let (_, hashed) = lookup(
+            &cache,
+            &file,
+            &[DigestType::BLAKE2b],
+            &Blake2Params::default(),
+        );
+        assert_eq!(hashed, [DigestType::BLAKE2b]);
+        let mut labels: Vec<String> = cache
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(_, label)| label.clone())
+            .collect();
+        labels.sort();
+        assert_eq!(labels, ["BLAKE2b", "BLAKE2b-256"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
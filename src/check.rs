@@ -0,0 +1,532 @@
+//! Verification of checksum files, as printed by this program or by GNU coreutils.
+
+use crate::blake2::Blake2Params;
+use crate::encoding::to_hex_lowercase;
+use crate::input::IoOptions;
+use crate::{CheckedFile, DigestType, open_input, perform_hash, warn_insecure};
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+
+/// A single, properly formatted line of a checksum file.
+#[derive(Debug)]
+struct ChecksumLine {
+    /// The expected hash value, normalized to lowercase hexadecimal.
+    expected: String,
+    /// The path of the file whose hash should be recalculated.
+    file_path: Utf8PathBuf,
+    /// The algorithm named by a BSD-style tagged line, if any, with the BLAKE2 output length in
+    /// bytes if the label included one.
+    tagged: Option<(DigestType, Option<usize>)>,
+}
+
+impl ChecksumLine {
+    /// Parses one line of a checksum file.
+    ///
+    /// The following forms are understood:
+    ///
+    /// - `<hex>: <path>` as printed by [`crate::output::Printer`]
+    /// - `<hex>  <path>` as printed by `sha256sum` in text mode
+    /// - `<hex> *<path>` as printed by `sha256sum` in binary mode
+    /// - `<ALGORITHM> (<path>) = <hex>` as printed with `--tag` or by BSD tools
+    ///
+    /// Like coreutils, a leading backslash marks a path in which `\\` and `\n` are escaped.
+    ///
+    /// Returns `None` if the line is not in any of these forms.
+    fn parse(line: &str) -> Option<Self> {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if let Some(tagged) = Self::parse_tagged(line, escaped) {
+            return Some(tagged);
+        }
+        let hex_len = line
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(line.len());
+        if hex_len == 0 || !hex_len.is_multiple_of(2) {
+            return None;
+        }
+        let (hex, rest) = line.split_at(hex_len);
+        let path = rest
+            .strip_prefix(": ")
+            .or_else(|| rest.strip_prefix("  "))
+            .or_else(|| rest.strip_prefix(" *"))?;
+        if path.is_empty() {
+            return None;
+        }
+        let path = if escaped {
+            unescape_path(path)?
+        } else {
+            path.to_string()
+        };
+        Some(ChecksumLine {
+            expected: hex.to_ascii_lowercase(),
+            file_path: Utf8PathBuf::from(path),
+            tagged: None,
+        })
+    }
+
+    /// Parses a BSD-style `<ALGORITHM> (<path>) = <hex>` line.
+    fn parse_tagged(line: &str, escaped: bool) -> Option<Self> {
+        let (label, rest) = line.split_once(" (")?;
+        let tagged = DigestType::from_name(label)
+            .map(|digest| (digest, None))
+            .or_else(|| Blake2Params::parse_label(label))?;
+        let (path, hex) = rest.rsplit_once(") = ")?;
+        if path.is_empty() || hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let path = if escaped {
+            unescape_path(path)?
+        } else {
+            path.to_string()
+        };
+        Some(ChecksumLine {
+            expected: hex.to_ascii_lowercase(),
+            file_path: Utf8PathBuf::from(path),
+            tagged: Some(tagged),
+        })
+    }
+
+    /// Determines the hash algorithms that may have been used for this line.
+    ///
+    /// A tagged line names its own algorithm, which must be one of `digests` if any were given
+    /// on the command line. An untagged line could have been calculated by any of `digests`, or
+    /// by any supported algorithm if `digests` is empty, that matches the length of the
+    /// expected hash value; several algorithms share a length, e.g. SHA-256 and BLAKE3, so all
+    /// of them are returned and the line matches if any of them does.
+    ///
+    /// The BLAKE2 output length is taken from the tag, from `blake2`, or from the length of the
+    /// expected hash value, in that order; the last is only tried if no other algorithm in
+    /// `digests` matches. The remaining BLAKE2 parameters come from `blake2`.
+    fn candidates(
+        &self,
+        digests: &[DigestType],
+        blake2: &Blake2Params,
+    ) -> Option<(Vec<DigestType>, Blake2Params)> {
+        let size = self.expected.len() / 2;
+        if let Some((tagged, length)) = &self.tagged {
+            if !digests.is_empty() && !digests.contains(tagged) {
+                return None;
+            }
+            let blake2 = Blake2Params {
+                length: *length,
+                ..blake2.clone()
+            };
+            return Self::accept(std::slice::from_ref(tagged), blake2, size);
+        }
+        if digests.is_empty() {
+            return Self::accept(DigestType::value_variants(), blake2.clone(), size);
+        }
+        Self::accept(digests, blake2.clone(), size).or_else(|| {
+            let blake2 = Blake2Params {
+                length: blake2.length.or(Some(size)),
+                ..blake2.clone()
+            };
+            Self::accept(digests, blake2, size)
+        })
+    }
+
+    /// Returns those of `digests` that produce hash values of `size` bytes with `blake2`, or
+    /// `None` if there are none.
+    fn accept(
+        digests: &[DigestType],
+        blake2: Blake2Params,
+        size: usize,
+    ) -> Option<(Vec<DigestType>, Blake2Params)> {
+        let accepted: Vec<DigestType> = digests
+            .iter()
+            .filter(|digest| {
+                blake2.output_size(digest).is_none()
+                    || blake2.validate(std::slice::from_ref(digest)).is_ok()
+            })
+            .filter(|digest| digest.output_size(&blake2) == size)
+            .cloned()
+            .collect();
+        (!accepted.is_empty()).then_some((accepted, blake2))
+    }
+}
+
+/// Reverses the escaping coreutils applies to paths containing backslashes or newlines.
+pub fn unescape_path(path: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next()? {
+                '\\' => unescaped.push('\\'),
+                'n' => unescaped.push('\n'),
+                _ => return None,
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    Some(unescaped)
+}
+
+/// Options controlling what is reported while checking, and which problems are fatal.
+#[derive(Debug)]
+pub struct CheckOptions<'a> {
+    /// Don't print `OK` for each successfully verified file.
+    pub quiet: bool,
+    /// Don't print anything; only the exit status reports the outcome.
+    pub status: bool,
+    /// Fail if any line of a checksum file is improperly formatted.
+    pub strict: bool,
+    /// Neither fail nor report for listed files that don't exist.
+    pub ignore_missing: bool,
+    /// Print a warning for every improperly formatted line.
+    pub warn: bool,
+    /// Check lines that use a cryptographically broken algorithm instead of refusing them.
+    pub allow_insecure: bool,
+    /// How listed files are read.
+    pub io: IoOptions<'a>,
+}
+
+/// Running totals over all checksum files, used for the closing summary and exit status.
+#[derive(Debug, Default)]
+struct CheckSummary {
+    /// Lines that could not be parsed.
+    improperly_formatted: usize,
+    /// Files whose hash differed from the expected value.
+    mismatched: usize,
+    /// Files that could not be opened or read.
+    unreadable: usize,
+    /// Files whose hash matched the expected value.
+    verified: usize,
+    /// Lines that were not checked because they use an insecure algorithm.
+    refused: usize,
+    /// Insecure algorithms that have already been warned about.
+    warned_insecure: Vec<DigestType>,
+}
+
+/// Verifies every checksum file in `files`, printing `OK` or `FAILED` for each listed file.
+///
+/// Exit status, depending on `options`:
+///
+/// | Condition                                          | Default | `--strict` | `--ignore-missing` |
+/// |----------------------------------------------------|---------|------------|--------------------|
+/// | every listed file matched                          | 0       | 0          | 0                  |
+/// | a computed hash did not match                      | 1       | 1          | 1                  |
+/// | a listed file could not be read                    | 1       | 1          | 1                  |
+/// | a line uses an insecure algorithm (1)              | 1       | 1          | 1                  |
+/// | a listed file does not exist                       | 1       | 1          | 0 (skipped)        |
+/// | some lines are improperly formatted                | 0       | 1          | 0                  |
+/// | no properly formatted line in a checksum file      | 1       | 1          | 1                  |
+/// | a checksum file could not be read                  | 1       | 1          | 1                  |
+/// | no file in a checksum file was verified            | 0       | 0          | 1                  |
+///
+/// (1) unless `--allow-insecure` was given, in which case the line is checked normally.
+///
+/// `--quiet`, `--status` and `--warn` only change what is printed, never the exit status.
+pub fn check_files(
+    files: &[Utf8PathBuf],
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    options: &CheckOptions,
+) -> ExitCode {
+    let mut summary = CheckSummary::default();
+    let mut success = true;
+    for checksum_file in files {
+        let verified_before = summary.verified;
+        match check_file(checksum_file, digests, blake2, options, &mut summary) {
+            Ok(0) => {
+                if !options.status {
+                    eprintln!(
+                        "{}: no properly formatted checksum lines found",
+                        checksum_file
+                    );
+                }
+                success = false;
+            }
+            Ok(_) if options.ignore_missing && summary.verified == verified_before => {
+                if !options.status {
+                    eprintln!("{}: no file was verified", checksum_file);
+                }
+                success = false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if !options.status {
+                    eprintln!("{}: unable to read checksum file: {}", checksum_file, e);
+                }
+                success = false;
+            }
+        }
+    }
+    if !options.status {
+        print_summary(&summary);
+    }
+    if options.strict && summary.improperly_formatted > 0 {
+        success = false;
+    }
+    if success && summary.mismatched == 0 && summary.unreadable == 0 && summary.refused == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Verifies the lines of a single checksum file and returns how many were properly formatted.
+fn check_file(
+    checksum_file: &Utf8PathBuf,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    options: &CheckOptions,
+    summary: &mut CheckSummary,
+) -> io::Result<usize> {
+    let reader = io::BufReader::new(open_input(checksum_file)?);
+    let mut properly_formatted = 0;
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((entry, (candidates, blake2))) = ChecksumLine::parse(line).and_then(|entry| {
+            entry
+                .candidates(digests, blake2)
+                .map(|candidates| (entry, candidates))
+        }) else {
+            if options.warn && !options.status {
+                eprintln!(
+                    "{}: {}: improperly formatted checksum line",
+                    checksum_file,
+                    line_number + 1
+                );
+            }
+            summary.improperly_formatted += 1;
+            continue;
+        };
+        properly_formatted += 1;
+        let secure: Vec<DigestType> = candidates
+            .iter()
+            .filter(|digest| !digest.is_insecure())
+            .cloned()
+            .collect();
+        let candidates = if options.allow_insecure || secure.is_empty() {
+            candidates
+        } else {
+            // A secure algorithm of the same length is the more likely one anyway.
+            secure
+        };
+        if let Some(digest) = candidates.iter().find(|digest| digest.is_insecure()) {
+            if !options.allow_insecure {
+                if !options.status {
+                    eprintln!(
+                        "{}: {}: {} is insecure, pass --allow-insecure to check it",
+                        checksum_file,
+                        line_number + 1,
+                        digest.name()
+                    );
+                }
+                summary.refused += 1;
+                continue;
+            }
+            for digest in candidates.iter().filter(|digest| digest.is_insecure()) {
+                if !options.status && !summary.warned_insecure.contains(digest) {
+                    warn_insecure(digest);
+                    summary.warned_insecure.push(digest.clone());
+                }
+            }
+        }
+        check_entry(&entry, &candidates, &blake2, options, summary);
+    }
+    Ok(properly_formatted)
+}
+
+/// Recalculates the hash of the file named by `entry` with each of `candidates`, in a single
+/// read, and prints the outcome; the file matches if any of the hash values does.
+fn check_entry(
+    entry: &ChecksumLine,
+    candidates: &[DigestType],
+    blake2: &Blake2Params,
+    options: &CheckOptions,
+    summary: &mut CheckSummary,
+) {
+    let CheckedFile {
+        file_path: path_buf,
+        hashable: result,
+    } = CheckedFile::new(&entry.file_path);
+    if let Err(err) = result {
+        if options.ignore_missing && !path_buf.exists() {
+            return;
+        }
+        if !options.status {
+            eprintln!("{}: unable to hash this file", err);
+            println!("{}: FAILED open or read", path_buf);
+        }
+        summary.unreadable += 1;
+        return;
+    }
+    let matched = perform_hash(&path_buf, candidates, blake2, &options.io).map(|hash_values| {
+        hash_values
+            .iter()
+            .any(|hash_value| to_hex_lowercase(hash_value) == entry.expected)
+    });
+    match matched {
+        Ok(true) => {
+            if !options.quiet && !options.status {
+                println!("{}: OK", path_buf);
+            }
+            summary.verified += 1;
+        }
+        Ok(false) => {
+            if !options.status {
+                println!("{}: FAILED", path_buf);
+            }
+            summary.mismatched += 1;
+        }
+        Err(e) => {
+            if !options.status {
+                eprintln!("{}: error during hashing: {}", path_buf, e);
+                println!("{}: FAILED open or read", path_buf);
+            }
+            summary.unreadable += 1;
+        }
+    }
+}
+
+/// Prints coreutils-style warnings about everything that went wrong to stderr.
+fn print_summary(summary: &CheckSummary) {
+    if summary.improperly_formatted > 0 {
+        eprintln!(
+            "WARNING: {} {} improperly formatted",
+            summary.improperly_formatted,
+            plural(summary.improperly_formatted, "line is", "lines are")
+        );
+    }
+    warn_unreadable(summary.unreadable);
+    if summary.refused > 0 {
+        eprintln!(
+            "WARNING: {} {} not checked because of an insecure algorithm",
+            summary.refused,
+            plural(summary.refused, "line was", "lines were")
+        );
+    }
+    warn_mismatched(summary.mismatched);
+}
+
+/// Warns that `unreadable` listed files could not be read, if there were any.
+pub fn warn_unreadable(unreadable: usize) {
+    if unreadable > 0 {
+        eprintln!(
+            "WARNING: {} listed {} could not be read",
+            unreadable,
+            plural(unreadable, "file", "files")
+        );
+    }
+}
+
+/// Warns that `mismatched` computed checksums did not match, if there were any.
+pub fn warn_mismatched(mismatched: usize) {
+    if mismatched > 0 {
+        eprintln!(
+            "WARNING: {} computed {} did NOT match",
+            mismatched,
+            plural(mismatched, "checksum", "checksums")
+        );
+    }
+}
+
+/// Returns `singular` if `count` is 1 and `plural` otherwise, e.g. for "1 line" and "2 lines".
+pub fn plural<'a>(count: usize, singular: &'a str, plural: &'a str) -> &'a str {
+    if count == 1 { singular } else { plural }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn parses_untagged_forms() {
+        for line in [
+            format!("{}: a b", SHA256_ABC),
+            format!("{}  a b", SHA256_ABC),
+            format!("{} *a b", SHA256_ABC),
+            format!("{}: a b", SHA256_ABC.to_ascii_uppercase()),
+        ] {
+            let entry = ChecksumLine::parse(&line).unwrap();
+            assert_eq!(entry.expected, SHA256_ABC, "{}", line);
+            assert_eq!(entry.file_path, "a b", "{}", line);
+            assert!(entry.tagged.is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_tagged_forms() {
+        let entry = ChecksumLine::parse(&format!("SHA256 (a) = {}", SHA256_ABC)).unwrap();
+        assert_eq!(entry.tagged, Some((DigestType::SHA256, None)));
+        assert_eq!(entry.file_path, "a");
+
+        let entry = ChecksumLine::parse("BLAKE2b-256 (x (1).txt) = 00ff").unwrap();
+        assert_eq!(entry.tagged, Some((DigestType::BLAKE2b, Some(32))));
+        assert_eq!(entry.file_path, "x (1).txt");
+
+        let entry = ChecksumLine::parse(&format!("SHA512t256 (a) = {}", SHA256_ABC)).unwrap();
+        assert_eq!(entry.tagged, Some((DigestType::SHA512_256, None)));
+    }
+
+    #[test]
+    fn untagged_lines_try_every_algorithm_of_their_length() {
+        let entry = ChecksumLine::parse(&format!("{}: a", SHA256_ABC)).unwrap();
+        let blake2 = Blake2Params::default();
+        let digests = [DigestType::SHA256, DigestType::SHA512, DigestType::BLAKE3];
+        let (candidates, _) = entry.candidates(&digests, &blake2).unwrap();
+        assert_eq!(candidates, [DigestType::SHA256, DigestType::BLAKE3]);
+
+        let (candidates, _) = entry.candidates(&[], &blake2).unwrap();
+        assert!(candidates.contains(&DigestType::SHA256));
+        assert!(candidates.contains(&DigestType::SHA3_256));
+        assert!(candidates.contains(&DigestType::BLAKE3));
+        assert!(!candidates.contains(&DigestType::SHA512));
+
+        assert!(entry.candidates(&[DigestType::SHA512], &blake2).is_none());
+    }
+
+    #[test]
+    fn tagged_lines_have_one_candidate() {
+        let entry = ChecksumLine::parse(&format!("BLAKE3 (a) = {}", SHA256_ABC)).unwrap();
+        let blake2 = Blake2Params::default();
+        let (candidates, _) = entry.candidates(&[], &blake2).unwrap();
+        assert_eq!(candidates, [DigestType::BLAKE3]);
+        assert!(entry.candidates(&[DigestType::SHA256], &blake2).is_none());
+    }
+
+    #[test]
+    fn blake2_length_falls_back_to_the_hash_value() {
+        let entry = ChecksumLine::parse(&format!("{}: a", SHA256_ABC)).unwrap();
+        let (candidates, blake2) = entry
+            .candidates(&[DigestType::BLAKE2b], &Blake2Params::default())
+            .unwrap();
+        assert_eq!(candidates, [DigestType::BLAKE2b]);
+        assert_eq!(blake2.length, Some(32));
+    }
+
+    #[test]
+    fn unescapes_paths() {
+        let entry = ChecksumLine::parse(&format!("\\{}  a\\nb\\\\c", SHA256_ABC)).unwrap();
+        assert_eq!(entry.file_path, "a\nb\\c");
+        assert!(ChecksumLine::parse(&format!("\\{}  a\\tb", SHA256_ABC)).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "",
+            "a",
+            "abc: a",
+            &format!("{}:a", SHA256_ABC),
+            &format!("{}: ", SHA256_ABC),
+            "SHA256 (a) = xyz",
+            "UNKNOWN (a) = 00",
+        ] {
+            assert!(ChecksumLine::parse(line).is_none(), "{:?}", line);
+        }
+    }
+}
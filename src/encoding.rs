@@ -0,0 +1,272 @@
+//! The encodings hash values can be printed in.
+
+use crate::DigestType;
+use crate::blake2::Blake2Params;
+use clap::ValueEnum;
+
+/// The encodings hash values can be printed in.
+#[derive(Debug, ValueEnum, Clone, PartialEq)]
+pub enum Encoding {
+    /// Lowercase hexadecimal
+    Hex,
+    /// Uppercase hexadecimal
+    UpperHex,
+    /// Base64 with padding (RFC 4648, section 4)
+    Base64,
+    /// URL- and filename-safe base64 without padding (RFC 4648, section 5)
+    Base64url,
+    /// Uppercase base32 with padding (RFC 4648, section 6)
+    Base32,
+    /// The base-32 encoding used by Nix, as printed by `nix-hash --base32`
+    NixBase32,
+    /// Subresource Integrity, e.g. "sha256-<base64>" (sha256, sha384 and sha512 only)
+    Sri,
+    /// OCI content digests, e.g. "sha256:<hex>" (sha256 and sha512 only)
+    Oci,
+    /// CIDv1 content identifiers with the raw codec in base32, as used by IPFS, e.g. "bafkrei…"
+    Cid,
+    /// The bare bytes of each hash value without file names or newlines (text format only)
+    Raw,
+}
+
+impl Encoding {
+    /// Encodes `hash_value`, which was calculated by `digest`.
+    ///
+    /// [`Encoding::Raw`] isn't text, so its hash values are written as they are instead.
+    pub fn encode(&self, digest: &DigestType, hash_value: &[u8]) -> String {
+        match self {
+            Encoding::Hex => to_hex_lowercase(hash_value),
+            Encoding::UpperHex => data_encoding::HEXUPPER.encode(hash_value),
+            Encoding::Base64 => data_encoding::BASE64.encode(hash_value),
+            Encoding::Base64url => data_encoding::BASE64URL_NOPAD.encode(hash_value),
+            Encoding::Base32 => data_encoding::BASE32.encode(hash_value),
+            Encoding::NixBase32 => to_nix_base32(hash_value),
+            Encoding::Sri => to_sri(digest, hash_value),
+            Encoding::Oci => to_oci(digest, hash_value),
+            Encoding::Cid => to_cid(digest, hash_value),
+            Encoding::Raw => unreachable!("raw hash values aren't encoded"),
+        }
+    }
+
+    /// Checks that every algorithm in `digests` can be printed in this encoding.
+    pub fn validate(&self, digests: &[DigestType]) -> Result<(), String> {
+        match self {
+            Encoding::Sri => match digests.iter().find(|digest| digest.sri_name().is_none()) {
+                Some(digest) => Err(format!(
+                    "{} has no Subresource Integrity name, use sha256, sha384 or sha512",
+                    digest.name()
+                )),
+                None => Ok(()),
+            },
+            Encoding::Oci => match digests.iter().find(|digest| digest.oci_name().is_none()) {
+                Some(digest) => Err(format!(
+                    "{} is not registered for OCI content digests, use sha256 or sha512",
+                    digest.name()
+                )),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks that hash values of every algorithm in `digests` calculated with `blake2` can be
+/// wrapped in a multihash, as for `--multihash` and [`Encoding::Cid`].
+pub fn validate_multihash(digests: &[DigestType], blake2: &Blake2Params) -> Result<(), String> {
+    if blake2.is_keyed()
+        && digests
+            .iter()
+            .any(|digest| blake2.output_size(digest).is_some())
+    {
+        return Err(String::from(
+            "multihash has no codes for BLAKE2 with --key, --salt or --personal",
+        ));
+    }
+    Ok(())
+}
+
+/// Converts a Vec<u8> into a lowercase hexadecimal string.
+///
+/// # Example
+///
+/// ```rust
+/// let vec_hash: Vec<u8> = vec![68, 201, 46];
+/// assert_eq!(to_hex_lowercase(&vec_hash, String::from("44c92e"));
+/// ```
+pub fn to_hex_lowercase(vec_hash: &[u8]) -> String {
+    vec_hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The alphabet of Nix's base-32 encoding, which omits `e`, `o`, `u` and `t`.
+const NIX_BASE32_ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Converts a hash value into the base-32 encoding used by Nix.
+///
+/// Unlike RFC 4648, Nix encodes the bits starting from the end of the hash value, so the
+/// result can't be produced with a standard base-32 encoder.
+fn to_nix_base32(hash_value: &[u8]) -> String {
+    let len = (hash_value.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let bit = n * 5;
+            let (i, j) = (bit / 8, bit % 8);
+            let low = hash_value[i] >> j;
+            let high = hash_value
+                .get(i + 1)
+                .map_or(0, |byte| byte.checked_shl(8 - j as u32).unwrap_or(0));
+            NIX_BASE32_ALPHABET[((low | high) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Converts a hash value into a Subresource Integrity string, e.g. `sha256-<base64>`.
+///
+/// `digest` must have an [`DigestType::sri_name`].
+fn to_sri(digest: &DigestType, hash_value: &[u8]) -> String {
+    format!(
+        "{}-{}",
+        digest.sri_name().expect("checked by Encoding::validate"),
+        data_encoding::BASE64.encode(hash_value)
+    )
+}
+
+/// Converts a hash value into an OCI content digest, e.g. `sha256:<hex>`.
+///
+/// `digest` must have an [`DigestType::oci_name`].
+fn to_oci(digest: &DigestType, hash_value: &[u8]) -> String {
+    format!(
+        "{}:{}",
+        digest.oci_name().expect("checked by Encoding::validate"),
+        to_hex_lowercase(hash_value)
+    )
+}
+
+/// Prefixes a hash value with the varint-encoded multicodec code of `digest` and its length,
+/// producing a self-describing multihash.
+pub fn to_multihash(digest: &DigestType, hash_value: &[u8]) -> Vec<u8> {
+    let mut multihash = Vec::with_capacity(hash_value.len() + 6);
+    push_varint(&mut multihash, digest.multihash_code(hash_value.len()));
+    push_varint(&mut multihash, hash_value.len() as u64);
+    multihash.extend_from_slice(hash_value);
+    multihash
+}
+
+/// Appends `value` as an unsigned varint (LEB128), as used by multiformats.
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// The multicodec code of CID version 1.
+const CID_V1: u8 = 0x01;
+
+/// The multicodec code of the raw binary codec, for CIDs of plain file contents.
+const RAW_CODEC: u8 = 0x55;
+
+/// Converts a hash value into a CIDv1 with the raw codec, in multibase base32 (lowercase,
+/// unpadded, with the prefix `b`).
+fn to_cid(digest: &DigestType, hash_value: &[u8]) -> String {
+    let mut cid = vec![CID_V1, RAW_CODEC];
+    cid.extend(to_multihash(digest, hash_value));
+    format!(
+        "b{}",
+        data_encoding::BASE32_NOPAD
+            .encode(&cid)
+            .to_ascii_lowercase()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    #[test]
+    fn nix_base32_known_answers() {
+        assert_eq!(to_nix_base32(&[]), "");
+        assert_eq!(to_nix_base32(&[0xff]), "7z");
+        // `nix-hash --type sha256 --to-base32` of the SHA-256 of the empty string.
+        assert_eq!(
+            to_nix_base32(&sha2::Sha256::digest(b"")),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+        assert_eq!(
+            to_nix_base32(&sha1_abc()),
+            "kpcd173cq987hw957sx6m0868wv3x6d9"
+        );
+    }
+
+    #[test]
+    fn sri_known_answer() {
+        assert_eq!(
+            Encoding::Sri.encode(&DigestType::SHA256, &sha2::Sha256::digest(b"abc")),
+            "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+        );
+    }
+
+    #[test]
+    fn sri_requires_a_named_algorithm() {
+        assert!(Encoding::Sri.validate(&[DigestType::SHA384]).is_ok());
+        assert!(Encoding::Sri.validate(&[DigestType::BLAKE3]).is_err());
+    }
+
+    #[test]
+    fn multihash_prefixes() {
+        // sha2-256 is 0x12, followed by the length 32.
+        let hash_value = sha2::Sha256::digest(b"abc");
+        let multihash = to_multihash(&DigestType::SHA256, &hash_value);
+        assert_eq!(multihash[..2], [0x12, 0x20]);
+        assert_eq!(multihash[2..], hash_value[..]);
+        // blake2b-256 is 0xb220, which takes three bytes as a varint.
+        assert_eq!(
+            to_multihash(&DigestType::BLAKE2b, &[0; 32])[..4],
+            [0xa0, 0xe4, 0x02, 0x20]
+        );
+    }
+
+    #[test]
+    fn varints() {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0x80, 0x01]),
+            (0x3fff, &[0xff, 0x7f]),
+            (0x4000, &[0x80, 0x80, 0x01]),
+        ] {
+            let mut bytes = Vec::new();
+            push_varint(&mut bytes, value);
+            assert_eq!(bytes, expected, "{:#x}", value);
+        }
+    }
+
+    #[test]
+    fn cid_known_answer() {
+        // `ipfs add --cid-version 1 --raw-leaves` of a file containing `abc`.
+        assert_eq!(
+            Encoding::Cid.encode(&DigestType::SHA256, &sha2::Sha256::digest(b"abc")),
+            "bafkreif2pall7dybz7vecqka3zo24irdwabwdi4wc55jznaq75q7eaavvu"
+        );
+    }
+
+    #[test]
+    fn keyed_blake2_has_no_multihash() {
+        let keyed = Blake2Params {
+            key: vec![0],
+            ..Blake2Params::default()
+        };
+        assert!(validate_multihash(&[DigestType::BLAKE2b], &Blake2Params::default()).is_ok());
+        assert!(validate_multihash(&[DigestType::BLAKE2b], &keyed).is_err());
+        assert!(validate_multihash(&[DigestType::SHA256], &keyed).is_ok());
+    }
+
+    /// The SHA-1 of `abc`, spelled out so the test doesn't need the `insecure` feature.
+    fn sha1_abc() -> Vec<u8> {
+        data_encoding::HEXLOWER
+            .decode(b"a9993e364706816aba3e25717850c26c9cd0d89d")
+            .unwrap()
+    }
+}
@@ -0,0 +1,130 @@
+//! Reading of the files to be hashed, either memory-mapped or through a buffer.
+
+use crate::STDIN_PATH;
+use crate::cache::Cache;
+use crate::progress::Progress;
+use camino::Utf8Path;
+use clap::ValueEnum;
+use memmap2::Mmap;
+use std::fs::File;
+use std::str::FromStr;
+
+/// How the contents of files are read.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq)]
+pub enum IoStrategy {
+    /// Memory-map regular files of at least 1 MiB and read everything else through a buffer
+    Auto,
+    /// Memory-map every regular file that isn't empty
+    Mmap,
+    /// Read every file through a buffer, e.g. for network filesystems
+    Buffered,
+}
+
+/// Options controlling how the contents of files are read.
+#[derive(Debug, Clone, Copy)]
+pub struct IoOptions<'a> {
+    pub strategy: IoStrategy,
+    /// The size of the buffer files are read into, and of the chunks memory-mapped files are
+    /// hashed in.
+    pub buffer_size: usize,
+    /// Where the hash values of unchanged files are looked up instead of reading them.
+    pub cache: Option<&'a Cache>,
+    /// Where the number of bytes and files hashed is counted, if progress is reported.
+    pub progress: Option<&'a Progress>,
+}
+
+/// With [`IoStrategy::Auto`], files of at least this many bytes are memory-mapped.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+impl IoOptions<'_> {
+    /// Memory-maps `path` if the strategy calls for it.
+    ///
+    /// Returns `None` if the file should be read through a buffer instead, including when
+    /// mapping it fails, e.g. because its filesystem doesn't support memory mapping. Errors
+    /// are only returned if the file can't be opened at all.
+    ///
+    /// Like any program that maps files, this one is killed by `SIGBUS` if a mapped file is
+    /// truncated while it is being hashed; use [`IoStrategy::Buffered`] for files that may be
+    /// modified concurrently.
+    pub fn map(&self, path: &Utf8Path) -> std::io::Result<Option<Mmap>> {
+        if self.strategy == IoStrategy::Buffered || path == STDIN_PATH {
+            return Ok(None);
+        }
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        // Pipes, devices and files in /proc report sizes that don't match their contents, and
+        // mapping an empty file fails on some platforms.
+        if !metadata.is_file() || metadata.len() == 0 {
+            return Ok(None);
+        }
+        if self.strategy == IoStrategy::Auto && metadata.len() < MMAP_THRESHOLD {
+            return Ok(None);
+        }
+        // SAFETY: the mapping is only read, and the caveat about concurrent truncation is
+        // documented above.
+        Ok(unsafe { Mmap::map(&file) }.ok())
+    }
+}
+
+/// A number of bytes given on the command line, optionally with a binary suffix: `K`, `M` or
+/// `G`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digits, multiplier) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        let size = digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| format!("{:?} is not a size such as 65536, 64K or 1M", s))?;
+        if size == 0 {
+            return Err(String::from("the size must be at least 1 byte"));
+        }
+        Ok(ByteSize(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_sizes() {
+        for (s, expected) in [
+            ("1", 1),
+            ("65536", 65536),
+            ("64K", 64 << 10),
+            ("64k", 64 << 10),
+            ("4M", 4 << 20),
+            ("1G", 1 << 30),
+        ] {
+            assert_eq!(s.parse::<ByteSize>().unwrap().0, expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_byte_sizes() {
+        for s in [
+            "",
+            "K",
+            "0",
+            "0K",
+            "-1",
+            "1.5M",
+            "1T",
+            "64KB",
+            "99999999999999999999G",
+        ] {
+            assert!(s.parse::<ByteSize>().is_err(), "{}", s);
+        }
+    }
+}
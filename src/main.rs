@@ -1,17 +1,214 @@
+use blake2::{Blake2Params, HexBytes};
+use cache::Cache;
 use camino::Utf8PathBuf;
-use clap::{Parser, ValueEnum};
+use clap::{ArgGroup, CommandFactory, Parser, ValueEnum};
+use encoding::Encoding;
+use input::{ByteSize, IoOptions, IoStrategy};
+use output::{Outcome, OutputFormat, Printer};
+use progress::{Progress, ProgressReader, Reporter};
 use sha2::Digest;
-use std::io::{self};
+use sri::Integrity;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+mod attributes;
+mod blake2;
+mod cache;
+mod check;
+mod encoding;
+mod input;
+mod nar;
+mod oci;
+mod output;
+mod progress;
+mod sri;
+mod tree;
+mod walk;
 
 /// An enumeration of possible hash algorithms supported by this program.
-#[derive(Debug, ValueEnum, Clone)]
+#[derive(Debug, ValueEnum, Clone, PartialEq)]
 enum DigestType {
+    /// Calculate the SHA224 hash for each file
+    SHA224,
     /// Calculate the SHA256 hash for each file
     SHA256,
+    /// Calculate the SHA384 hash for each file
+    SHA384,
     /// Calculate the SHA512 hash for each file
     SHA512,
+    /// Calculate the SHA512/224 hash for each file
+    SHA512_224,
+    /// Calculate the SHA512/256 hash for each file
+    SHA512_256,
+    /// Calculate the SHA3-224 hash for each file
+    SHA3_224,
+    /// Calculate the SHA3-256 hash for each file
+    SHA3_256,
+    /// Calculate the SHA3-384 hash for each file
+    SHA3_384,
+    /// Calculate the SHA3-512 hash for each file
+    SHA3_512,
+    /// Calculate the BLAKE2b hash for each file, 512 bits unless --length is given
+    BLAKE2b,
+    /// Calculate the BLAKE2s hash for each file, 256 bits unless --length is given
+    BLAKE2s,
+    /// Calculate the BLAKE3 hash for each file, using all cores for large files
+    BLAKE3,
+    /// Calculate the MD5 hash for each file (broken, requires --allow-insecure)
+    #[cfg(feature = "insecure")]
+    MD5,
+    /// Calculate the SHA1 hash for each file (broken, requires --allow-insecure)
+    #[cfg(feature = "insecure")]
+    SHA1,
+    /// Calculate the RIPEMD-160 hash for each file (weak, requires --allow-insecure)
+    #[cfg(feature = "insecure")]
+    RIPEMD160,
 }
 
+impl DigestType {
+    /// Returns the name of this algorithm as used in BSD-style tagged output.
+    fn name(&self) -> &'static str {
+        match self {
+            DigestType::SHA224 => "SHA224",
+            DigestType::SHA256 => "SHA256",
+            DigestType::SHA384 => "SHA384",
+            DigestType::SHA512 => "SHA512",
+            DigestType::SHA512_224 => "SHA512/224",
+            DigestType::SHA512_256 => "SHA512/256",
+            DigestType::SHA3_224 => "SHA3-224",
+            DigestType::SHA3_256 => "SHA3-256",
+            DigestType::SHA3_384 => "SHA3-384",
+            DigestType::SHA3_512 => "SHA3-512",
+            DigestType::BLAKE2b => "BLAKE2b",
+            DigestType::BLAKE2s => "BLAKE2s",
+            DigestType::BLAKE3 => "BLAKE3",
+            #[cfg(feature = "insecure")]
+            DigestType::MD5 => "MD5",
+            #[cfg(feature = "insecure")]
+            DigestType::SHA1 => "SHA1",
+            #[cfg(feature = "insecure")]
+            DigestType::RIPEMD160 => "RIPEMD160",
+        }
+    }
+
+    /// Looks up an algorithm by the name used in BSD-style tagged output.
+    ///
+    /// FreeBSD's `SHA512t224`, `SHA512t256` and `RMD160` spellings are accepted as well.
+    fn from_name(name: &str) -> Option<Self> {
+        let name = match name {
+            "SHA512t224" => "SHA512/224",
+            "SHA512t256" => "SHA512/256",
+            "RMD160" => "RIPEMD160",
+            name => name,
+        };
+        DigestType::value_variants()
+            .iter()
+            .find(|variant| variant.name() == name)
+            .cloned()
+    }
+
+    /// Returns the name of this algorithm in a Subresource Integrity string, if it has one.
+    fn sri_name(&self) -> Option<&'static str> {
+        match self {
+            DigestType::SHA256 => Some("sha256"),
+            DigestType::SHA384 => Some("sha384"),
+            DigestType::SHA512 => Some("sha512"),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of this algorithm in an OCI content digest, if it is registered by the
+    /// OCI image specification.
+    fn oci_name(&self) -> Option<&'static str> {
+        match self {
+            DigestType::SHA256 => Some("sha256"),
+            DigestType::SHA512 => Some("sha512"),
+            _ => None,
+        }
+    }
+
+    /// Returns the multicodec code identifying this algorithm in a multihash of a hash value of
+    /// `size` bytes.
+    ///
+    /// BLAKE2 has a code per output length, e.g. `blake2b-256`, but none for keyed, salted or
+    /// personalized hashing, which must be ruled out separately.
+    fn multihash_code(&self, size: usize) -> u64 {
+        match self {
+            DigestType::SHA224 => 0x1013,
+            DigestType::SHA256 => 0x12,
+            DigestType::SHA384 => 0x20,
+            DigestType::SHA512 => 0x13,
+            DigestType::SHA512_224 => 0x1014,
+            DigestType::SHA512_256 => 0x1015,
+            DigestType::SHA3_224 => 0x17,
+            DigestType::SHA3_256 => 0x16,
+            DigestType::SHA3_384 => 0x15,
+            DigestType::SHA3_512 => 0x14,
+            DigestType::BLAKE2b => 0xb200 + size as u64,
+            DigestType::BLAKE2s => 0xb240 + size as u64,
+            DigestType::BLAKE3 => 0x1e,
+            #[cfg(feature = "insecure")]
+            DigestType::MD5 => 0xd5,
+            #[cfg(feature = "insecure")]
+            DigestType::SHA1 => 0x11,
+            #[cfg(feature = "insecure")]
+            DigestType::RIPEMD160 => 0x1053,
+        }
+    }
+
+    /// Returns true for algorithms with known practical attacks, which are only available with
+    /// the `insecure` feature and `--allow-insecure`.
+    fn is_insecure(&self) -> bool {
+        match self {
+            #[cfg(feature = "insecure")]
+            DigestType::MD5 | DigestType::SHA1 | DigestType::RIPEMD160 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the label printed by `--tag`, which for BLAKE2 includes a non-default length.
+    fn label(&self, blake2: &Blake2Params) -> String {
+        blake2
+            .label(self)
+            .unwrap_or_else(|| self.name().to_string())
+    }
+
+    /// Returns the size of a hash value calculated by this algorithm, in bytes.
+    fn output_size(&self, blake2: &Blake2Params) -> usize {
+        if let Some(size) = blake2.output_size(self) {
+            return size;
+        }
+        match self {
+            DigestType::SHA224 => <sha2::Sha224 as Digest>::output_size(),
+            DigestType::SHA256 => <sha2::Sha256 as Digest>::output_size(),
+            DigestType::SHA384 => <sha2::Sha384 as Digest>::output_size(),
+            DigestType::SHA512 => <sha2::Sha512 as Digest>::output_size(),
+            DigestType::SHA512_224 => <sha2::Sha512_224 as Digest>::output_size(),
+            DigestType::SHA512_256 => <sha2::Sha512_256 as Digest>::output_size(),
+            DigestType::SHA3_224 => <sha3::Sha3_224 as Digest>::output_size(),
+            DigestType::SHA3_256 => <sha3::Sha3_256 as Digest>::output_size(),
+            DigestType::SHA3_384 => <sha3::Sha3_384 as Digest>::output_size(),
+            DigestType::SHA3_512 => <sha3::Sha3_512 as Digest>::output_size(),
+            DigestType::BLAKE2b | DigestType::BLAKE2s => unreachable!("handled above"),
+            DigestType::BLAKE3 => <blake3::Hasher as Digest>::output_size(),
+            #[cfg(feature = "insecure")]
+            DigestType::MD5 => <md5::Md5 as Digest>::output_size(),
+            #[cfg(feature = "insecure")]
+            DigestType::SHA1 => <sha1::Sha1 as Digest>::output_size(),
+            #[cfg(feature = "insecure")]
+            DigestType::RIPEMD160 => <ripemd::Ripemd160 as Digest>::output_size(),
+        }
+    }
+}
+
+/// The file name that stands for standard input, both on the command line and in output.
+const STDIN_PATH: &str = "-";
+
 /// Relevant data about files passed to this program on the command line.
 #[derive(Debug)]
 struct CheckedFile {
@@ -24,6 +221,7 @@ struct CheckedFile {
 
 impl CheckedFile {
     /// Checks the file pointed to by `path` to determine whether it's a regular file.
+    /// The path `-` refers to standard input and is always considered hashable.
     ///
     /// See [`camino::Utf8PathBuf`] for more details.
     ///
@@ -31,7 +229,7 @@ impl CheckedFile {
     ///
     /// This function will return an error if `path` is a directory or some other non-file.
     fn new(path: &Utf8PathBuf) -> Self {
-        if path.is_file() {
+        if path == STDIN_PATH || path.is_file() {
             CheckedFile {
                 file_path: path.clone(),
                 hashable: Ok(()),
@@ -50,72 +248,843 @@ impl CheckedFile {
     }
 }
 
+/// Exit status of each mode, appended to the output of --help.
+const EXIT_STATUS: &str = "\
+Exit status when hashing, including --tree and --nar:
+  0  every FILE was hashed
+  1  a FILE, or a file beneath a directory walked with -r, could not be read
+  1  with --store-xattr, additionally if the hash values of a file could not be
+     stored
+
+Exit status with --check:
+  0  every listed file was read and its hash matched
+  1  a hash did not match, or a listed file or checksum file could not be read,
+     or a checksum file contained no properly formatted line, or a line used an
+     insecure algorithm without --allow-insecure
+  1  with --strict, additionally if any line was improperly formatted
+  1  with --ignore-missing, additionally if no file of a checksum file was verified;
+     missing files themselves are skipped without failing
+
+Exit status with --verify-sri:
+  0  every FILE matched
+  1  a FILE did not match or could not be read
+
+Exit status with --verify-oci-layout:
+  0  every blob matched its digest and every descriptor matched its blob
+  1  a blob or descriptor did not match, or a file of a layout could not be read
+
+Exit status with --verify-xattr:
+  0  every stored hash value matched, was stale or was missing
+  1  a stored hash value did not match although the file was not modified since,
+     or a FILE could not be read";
+
 #[derive(Parser)]
-#[command(version, about="Calculate a cryptographic hash for one or more files.", long_about = None)]
+#[command(version, about="Calculate a cryptographic hash for one or more files.", long_about = None, after_long_help = EXIT_STATUS)]
+#[command(group(ArgGroup::new("walk").args(["recursive", "tree"])))]
 struct Cli {
-    /// The cryptographic hash to be calculated
-    #[arg(value_enum, short, long)]
-    digest: DigestType,
-    /// The file(s) for which the hash should be calculated
+    /// The cryptographic hash(es) to be calculated
+    ///
+    /// Repeat this option or separate algorithms with commas to calculate several hashes while
+    /// reading each file only once. With --check, lines must use one of the given algorithms,
+    /// which are otherwise inferred from the length of each listed hash.
+    #[arg(
+        value_enum,
+        short,
+        long,
+        value_delimiter = ',',
+        required_unless_present_any = ["check", "verify_sri", "verify_oci_layout", "verify_xattr", "prune_cache"]
+    )]
+    digest: Vec<DigestType>,
+    /// The digest length in bits for blake2b and blake2s, a multiple of 8
+    #[arg(short, long, value_name = "BITS")]
+    length: Option<usize>,
+    /// A key for keyed blake2b or blake2s hashing, in hexadecimal
+    #[arg(long, value_name = "HEX")]
+    key: Option<HexBytes>,
+    /// A salt for blake2b (up to 16 bytes) or blake2s (up to 8 bytes), in hexadecimal
+    #[arg(long, value_name = "HEX")]
+    salt: Option<HexBytes>,
+    /// A personalization string for blake2b (up to 16 bytes) or blake2s (up to 8 bytes), in
+    /// hexadecimal
+    #[arg(long, value_name = "HEX")]
+    personal: Option<HexBytes>,
+    /// Allow cryptographically broken algorithms such as md5 and sha1
+    ///
+    /// These are only available if this program was built with the `insecure` feature.
+    #[arg(long)]
+    allow_insecure: bool,
+    /// The encoding of printed hash values
+    #[arg(value_enum, long, default_value_t = Encoding::Hex, conflicts_with = "check")]
+    encoding: Encoding,
+    /// Hash the Nix Archive (NAR) serialization of each FILE, like nix-hash
+    ///
+    /// FILE may be a regular file, a symbolic link or a directory. Combine with
+    /// --encoding nix-base32 for the output of `nix-hash --type sha256 --base32`.
+    #[arg(long, conflicts_with_all = ["check", "walk"])]
+    nar: bool,
+    /// Print Subresource Integrity strings, e.g. "sha384-<base64>"; same as --encoding sri
+    #[arg(long, conflicts_with_all = ["check", "encoding"])]
+    sri: bool,
+    /// Check each FILE against Subresource Integrity metadata, e.g. "sha384-<base64>"
+    ///
+    /// The metadata may list several space-separated hash values, as in an integrity attribute
+    /// or package-lock.json. Only the strongest algorithm among them is used, and a file matches
+    /// if it matches any hash value of that algorithm.
+    #[arg(
+        long,
+        value_name = "INTEGRITY",
+        conflicts_with_all = ["check", "digest", "encoding", "sri", "multihash", "format", "tag", "nar", "walk"]
+    )]
+    verify_sri: Option<Integrity>,
+    /// Print OCI content digests, e.g. "sha256:<hex>"; same as --encoding oci
+    #[arg(long, conflicts_with_all = ["check", "encoding", "sri"])]
+    oci: bool,
+    /// Wrap hash values in a multihash, which names the algorithm and length, before encoding
+    /// them
+    #[arg(long, conflicts_with_all = ["check", "sri", "oci"])]
+    multihash: bool,
+    /// Print CIDv1 content identifiers with the raw codec, e.g. "bafkrei…"; same as --encoding cid
+    #[arg(long, conflicts_with_all = ["check", "encoding", "sri", "oci", "multihash"])]
+    cid: bool,
+    /// Verify each FILE as an OCI image layout directory
+    ///
+    /// Every blob beneath blobs/ must match the digest in its file name, and every descriptor
+    /// reachable from index.json must match the digest and size of a blob in the layout.
+    #[arg(
+        long,
+        conflicts_with_all = ["check", "digest", "encoding", "sri", "oci", "multihash", "cid", "verify_sri", "format", "tag", "nar", "walk"]
+    )]
+    verify_oci_layout: bool,
+    /// Store each hash value in a user.checksum.<algorithm> extended attribute of its FILE
+    ///
+    /// The modification time of the file is stored in user.checksum.mtime, so that
+    /// --verify-xattr can tell a modified file from a corrupted one.
+    #[arg(long, conflicts_with_all = ["check", "verify_sri", "verify_oci_layout", "nar", "tree"])]
+    store_xattr: bool,
+    /// Check each FILE against the hash values stored in its extended attributes by
+    /// --store-xattr
+    ///
+    /// Prints STALE for files modified since and MISSING for files without a stored hash value
+    /// of the given algorithms, or of any algorithm if --digest isn't given.
+    #[arg(
+        long,
+        conflicts_with_all = ["check", "encoding", "sri", "oci", "multihash", "cid", "verify_sri", "verify_oci_layout", "store_xattr", "format", "tag", "nar", "tree", "jobs", "unordered"]
+    )]
+    verify_xattr: bool,
+    /// The format in which hash values are printed
+    #[arg(value_enum, long, default_value_t = OutputFormat::Text, conflicts_with = "check")]
+    format: OutputFormat,
+    /// Create a BSD-style checksum, e.g. "SHA256 (FILE) = <hex>"; same as --format tag
+    #[arg(long, conflicts_with_all = ["check", "format"])]
+    tag: bool,
+    /// Hash every file beneath directories given as FILE, in sorted order
+    #[arg(short, long, conflicts_with = "check")]
+    recursive: bool,
+    /// Calculate a single digest over each directory given as FILE
+    ///
+    /// The digest covers the sorted relative paths, file types, executable bits and contents
+    /// of everything beneath the directory, using a versioned encoding (digest-tree-v1) that
+    /// is stable across machines.
+    #[arg(long, conflicts_with = "check")]
+    tree: bool,
+    /// With --recursive or --tree, only hash files matching this glob (may be repeated)
+    #[arg(long, value_name = "GLOB", requires = "walk")]
+    include: Vec<String>,
+    /// With --recursive or --tree, skip files and directories matching this glob (may be
+    /// repeated)
+    ///
+    /// Globs use .gitignore syntax relative to each directory given as FILE, so "target/"
+    /// skips every directory named target. Exclusions take precedence over --include.
+    #[arg(long, value_name = "GLOB", requires = "walk")]
+    exclude: Vec<String>,
+    /// With --recursive or --tree, skip files ignored by .gitignore, .ignore and git's exclude
+    /// files
+    #[arg(long, requires = "walk")]
+    respect_gitignore: bool,
+    /// How files are read
+    #[arg(value_enum, long, value_name = "STRATEGY", default_value_t = IoStrategy::Auto)]
+    io: IoStrategy,
+    /// The size of the buffer files are read into, e.g. 1M for network filesystems
+    #[arg(long, value_name = "SIZE", default_value = "64K")]
+    buffer_size: ByteSize,
+    /// Look up the hash values of unchanged files in this cache file, and store new ones in it
+    ///
+    /// Files count as unchanged while their device, inode, size and modification time stay the
+    /// same. --check, --verify-sri, --verify-oci-layout and --verify-xattr always read files and
+    /// only store their hash values. The cache is not used with --key, --salt or --personal.
+    #[arg(long, value_name = "FILE", env = "DIGEST_CACHE")]
+    cache: Option<Utf8PathBuf>,
+    /// Don't use the cache, even if DIGEST_CACHE is set
+    #[arg(long)]
+    no_cache: bool,
+    /// Hash every file again and replace its cache entries
+    #[arg(long, requires = "cache", conflicts_with = "no_cache")]
+    refresh_cache: bool,
+    /// Remove the cache entries of files that have changed or no longer exist, then exit
+    #[arg(
+        long,
+        requires = "cache",
+        conflicts_with_all = ["no_cache", "digest", "check", "verify_sri", "verify_oci_layout", "verify_xattr"]
+    )]
+    prune_cache: bool,
+    /// Report the number of bytes hashed, the throughput and the ETA on stderr, if it is a
+    /// terminal
+    #[arg(long)]
+    progress: bool,
+    /// Append a progress record to FILE once a second, as a line of JSON
+    ///
+    /// FILE may be a named pipe, or /dev/fd/N for a file descriptor inherited from the calling
+    /// program. The format is documented in the README.
+    #[arg(long, value_name = "FILE")]
+    progress_file: Option<Utf8PathBuf>,
+    /// Hash up to N files at a time [default: the number of available CPUs]
+    #[arg(short, long, value_name = "N", conflicts_with = "check")]
+    jobs: Option<NonZeroUsize>,
+    /// Print hash values as soon as they are calculated, rather than in the order of FILE
+    #[arg(long, conflicts_with = "check")]
+    unordered: bool,
+    /// Read checksums from the FILE(s) and check them
+    #[arg(short, long)]
+    check: bool,
+    /// Don't print OK for each successfully verified file
+    #[arg(long, requires = "check")]
+    quiet: bool,
+    /// Don't output anything, the exit status shows success
+    #[arg(long, requires = "check")]
+    status: bool,
+    /// Exit non-zero for improperly formatted checksum lines
+    #[arg(long, requires = "check")]
+    strict: bool,
+    /// Don't fail or report status for missing files
+    #[arg(long, requires = "check")]
+    ignore_missing: bool,
+    /// Warn about improperly formatted checksum lines
+    #[arg(short, long, requires = "check")]
+    warn: bool,
+    /// The file(s) for which the hash should be calculated, or the checksum file(s) with --check
+    ///
+    /// With no FILE, or when FILE is -, read standard input.
     #[arg(value_name="FILE", value_hint=clap::ValueHint::FilePath)]
     filename: Vec<Utf8PathBuf>,
 }
 
-fn main() {
-    let args = Cli::parse();
+fn main() -> ExitCode {
+    let mut args = Cli::parse();
+    if args.filename.is_empty() {
+        args.filename.push(Utf8PathBuf::from(STDIN_PATH));
+    }
+
+    if args.length.is_some_and(|bits| !bits.is_multiple_of(8)) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--length must be a multiple of 8",
+            )
+            .exit();
+    }
+    let blake2 = Blake2Params {
+        length: args.length.map(|bits| bits / 8),
+        key: args.key.map(|key| key.0).unwrap_or_default(),
+        salt: args.salt.map(|salt| salt.0).unwrap_or_default(),
+        personal: args.personal.map(|personal| personal.0).unwrap_or_default(),
+    };
+    let mut digests: Vec<DigestType> = Vec::with_capacity(args.digest.len());
+    for digest in args.digest {
+        if !digests.contains(&digest) {
+            digests.push(digest);
+        }
+    }
+    if let Err(msg) = blake2.validate(&digests) {
+        Cli::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, msg)
+            .exit();
+    }
+    for digest in digests.iter().filter(|digest| digest.is_insecure()) {
+        if !args.allow_insecure {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!(
+                        "{} is insecure, pass --allow-insecure to use it",
+                        digest.name()
+                    ),
+                )
+                .exit();
+        }
+        if !args.status {
+            warn_insecure(digest);
+        }
+    }
+
+    // Keyed hash values would be indistinguishable from unkeyed ones of the same algorithm.
+    if (args.store_xattr || args.verify_xattr) && blake2.is_keyed() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--store-xattr and --verify-xattr can't be used with --key, --salt or --personal",
+            )
+            .exit();
+    }
+
+    let encoding = if args.sri {
+        Encoding::Sri
+    } else if args.oci {
+        Encoding::Oci
+    } else if args.cid {
+        Encoding::Cid
+    } else {
+        args.encoding
+    };
+    if let Err(msg) = encoding.validate(&digests) {
+        Cli::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, msg)
+            .exit();
+    }
+
+    let walk_options = walk::WalkOptions {
+        include: args.include,
+        exclude: args.exclude,
+        respect_gitignore: args.respect_gitignore,
+    };
+    if let Err(msg) = walk_options.validate() {
+        Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, msg)
+            .exit();
+    }
+    let format = if args.tag {
+        OutputFormat::Tag
+    } else {
+        args.format
+    };
+    if args.multihash && matches!(encoding, Encoding::Sri | Encoding::Oci | Encoding::Cid) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--multihash can't be combined with --encoding sri, oci or cid",
+            )
+            .exit();
+    }
+    if (args.multihash || encoding == Encoding::Cid)
+        && let Err(msg) = encoding::validate_multihash(&digests, &blake2)
+    {
+        Cli::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, msg)
+            .exit();
+    }
+    if encoding == Encoding::Raw && format != OutputFormat::Text {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--encoding raw can only be used with --format text",
+            )
+            .exit();
+    }
+    // Verifying must read the contents, which a cache keyed by modification time can't vouch
+    // for, so those modes only store hash values.
+    let verifying =
+        args.check || args.verify_sri.is_some() || args.verify_oci_layout || args.verify_xattr;
+    // Keyed hash values would need the key in the cache, so they are never cached.
+    let cache = match &args.cache {
+        Some(path) if !args.no_cache && !blake2.is_keyed() => {
+            match Cache::open(path, args.refresh_cache || verifying) {
+                Ok(cache) => Some(cache),
+                Err(e) => Cli::command()
+                    .error(clap::error::ErrorKind::Io, format!("--cache: {}", e))
+                    .exit(),
+            }
+        }
+        _ => None,
+    };
+    if args.prune_cache
+        && let Some(cache) = &cache
+    {
+        println!("{} cache entries removed", cache.prune());
+        return save_cache(Some(cache), ExitCode::SUCCESS);
+    }
+
+    let progress_stream = args.progress_file.as_ref().map(|path| {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::Io,
+                        format!("--progress-file: {}: {}", path, e),
+                    )
+                    .exit()
+            })
+    });
+    let progress = Arc::new(Progress::default());
+    // Reports the final progress when dropped at the end of `main`.
+    let reporter = Reporter::start(Arc::clone(&progress), args.progress, progress_stream);
+
+    let io_options = IoOptions {
+        strategy: args.io,
+        buffer_size: args.buffer_size.0,
+        cache: cache.as_ref(),
+        progress: reporter.as_ref().map(|_| &*progress),
+    };
+
+    if args.check {
+        let options = check::CheckOptions {
+            quiet: args.quiet,
+            status: args.status,
+            strict: args.strict,
+            ignore_missing: args.ignore_missing,
+            warn: args.warn,
+            allow_insecure: args.allow_insecure,
+            io: io_options,
+        };
+        let exit_code = check::check_files(&args.filename, &digests, &blake2, &options);
+        return save_cache(cache.as_ref(), exit_code);
+    }
+
+    if let Some(integrity) = &args.verify_sri {
+        let exit_code = sri::verify_files(&args.filename, integrity, &io_options);
+        return save_cache(cache.as_ref(), exit_code);
+    }
+
+    if args.verify_oci_layout {
+        let exit_code = oci::verify_layouts(&args.filename, &io_options);
+        return save_cache(cache.as_ref(), exit_code);
+    }
+
+    let mut printer = Printer::new(format, encoding, args.multihash, &digests, &blake2);
+
+    if args.tree || args.nar {
+        let mut success = true;
+        for root in &args.filename {
+            let hash_values = if args.tree {
+                tree::tree_digest(root, &walk_options, &digests, &blake2, &io_options)
+            } else {
+                nar::nar_hash(root, &digests, &blake2, io_options.progress)
+            };
+            let outcome = match hash_values {
+                Ok(hash_values) => Outcome::Hashed(hash_values),
+                Err(e) => {
+                    success = false;
+                    Outcome::Failed(e)
+                }
+            };
+            printer.print(root, None, &outcome);
+        }
+        printer.finish();
+        let exit_code = if success {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+        return save_cache(cache.as_ref(), exit_code);
+    }
 
     let checked_files = args
         .filename
         .iter()
-        .map(CheckedFile::new)
+        .flat_map(|path| {
+            if args.recursive && path.is_dir() {
+                walk::walk_dir(path, &walk_options)
+            } else {
+                vec![CheckedFile::new(path)]
+            }
+        })
         .collect::<Vec<CheckedFile>>();
+    if reporter.is_some() {
+        let hashable = checked_files.iter().filter(|file| file.hashable.is_ok());
+        progress.set_total(hashable.count(), total_size(&checked_files));
+    }
+
+    if args.verify_xattr {
+        let exit_code = attributes::verify_files(&checked_files, &digests, &blake2, &io_options);
+        return save_cache(cache.as_ref(), exit_code);
+    }
+
+    let hash_options = HashOptions {
+        jobs: args.jobs.map_or_else(
+            || thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            NonZeroUsize::get,
+        ),
+        unordered: args.unordered,
+        store_xattr: args.store_xattr,
+    };
+    let success = hash_files(
+        &checked_files,
+        &digests,
+        &blake2,
+        &io_options,
+        &hash_options,
+        &mut printer,
+    );
+    printer.finish();
+    let exit_code = if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    };
+    save_cache(cache.as_ref(), exit_code)
+}
+
+/// Writes `cache` back to its file, if one is used, and passes on `exit_code`.
+///
+/// Failing to save the cache only costs time on the next run, so it is reported as a warning.
+fn save_cache(cache: Option<&Cache>, exit_code: ExitCode) -> ExitCode {
+    if let Some(cache) = cache
+        && let Err(e) = cache.save()
+    {
+        eprintln!("WARNING: unable to save the cache: {}", e);
+    }
+    exit_code
+}
+
+/// Returns the total size of the hashable `files`, or `None` if it can't be known in advance
+/// because one of them is standard input.
+fn total_size(files: &[CheckedFile]) -> Option<u64> {
+    files
+        .iter()
+        .filter(|file| file.hashable.is_ok())
+        .map(|file| {
+            if file.file_path == STDIN_PATH {
+                return None;
+            }
+            Some(file_metadata(&file.file_path).map_or(0, |metadata| metadata.len()))
+        })
+        .sum()
+}
 
-    hash_files(&checked_files, &args.digest);
+/// Warns on stderr that `digest` must not be relied upon for security.
+fn warn_insecure(digest: &DigestType) {
+    eprintln!(
+        "WARNING: {} is cryptographically broken and must not be relied upon to detect tampering",
+        digest.name()
+    );
 }
 
-fn hash_files(files: &Vec<CheckedFile>, digest: &DigestType) {
-    for file in files {
-        let CheckedFile {
-            file_path: path_buf,
-            hashable: result,
-        } = file;
-        match result {
-            Ok(()) => hash_file(path_buf, digest),
-            Err(err) => eprintln!("{}: unable to hash this file", err),
+/// Options controlling how the files given on the command line are hashed.
+#[derive(Debug)]
+struct HashOptions {
+    /// The number of files hashed at a time.
+    jobs: usize,
+    /// Print outcomes as soon as they are ready, rather than in the order of the files.
+    unordered: bool,
+    /// Store hash values in the extended attributes of their files.
+    store_xattr: bool,
+}
+
+/// Hashes `files` on worker threads and prints each outcome, in the order of `files` unless
+/// [`HashOptions::unordered`] is set, in which case outcomes are printed as soon as they are
+/// ready.
+///
+/// Returns whether every file was hashed, and its hash values stored if
+/// [`HashOptions::store_xattr`] is set.
+fn hash_files(
+    files: &[CheckedFile],
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+    options: &HashOptions,
+    printer: &mut Printer,
+) -> bool {
+    let next_file = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.min(files.len()) {
+            let sender = sender.clone();
+            let next_file = &next_file;
+            scope.spawn(move || {
+                loop {
+                    let index = next_file.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(index) else {
+                        break;
+                    };
+                    if sender
+                        .send((
+                            index,
+                            hash_file(file, digests, blake2, io_options, options.store_xattr),
+                        ))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Outcomes that arrived before those of earlier files, keyed by index into `files`.
+        let mut pending = BTreeMap::new();
+        let mut next_to_print = 0;
+        let mut success = true;
+        for (index, (metadata, outcome, store_failed)) in receiver {
+            if store_failed || matches!(outcome, Outcome::Unhashable(_) | Outcome::Failed(_)) {
+                success = false;
+            }
+            if options.unordered {
+                printer.print(&files[index].file_path, metadata.as_ref(), &outcome);
+                continue;
+            }
+            pending.insert(index, (metadata, outcome));
+            while let Some((metadata, outcome)) = pending.remove(&next_to_print) {
+                printer.print(&files[next_to_print].file_path, metadata.as_ref(), &outcome);
+                next_to_print += 1;
+            }
         }
+        success
+    })
+}
+
+/// Hashes `file`, returning its metadata along with the outcome, and stores the hash values in
+/// its extended attributes if `store_xattr` is set.
+///
+/// The returned flag is set if the hash values couldn't be stored.
+fn hash_file(
+    file: &CheckedFile,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+    store_xattr: bool,
+) -> (Option<std::fs::Metadata>, Outcome, bool) {
+    let CheckedFile {
+        file_path: path_buf,
+        hashable: result,
+    } = file;
+    if let Err(err) = result {
+        return (None, Outcome::Unhashable(err.clone()), false);
     }
+    let metadata = file_metadata(path_buf);
+    let outcome = match perform_hash(path_buf, digests, blake2, io_options) {
+        Ok(hash_values) => Outcome::Hashed(hash_values),
+        Err(e) => Outcome::Failed(e),
+    };
+    let mut store_failed = false;
+    if store_xattr
+        && let Outcome::Hashed(hash_values) = &outcome
+        && let Err(e) = attributes::store(path_buf, metadata.as_ref(), digests, blake2, hash_values)
+    {
+        eprintln!("{}: unable to store checksum attributes: {}", path_buf, e);
+        store_failed = true;
+    }
+    (metadata, outcome, store_failed)
 }
 
-fn hash_file(path_buf: &Utf8PathBuf, digest: &DigestType) {
-    match perform_hash(path_buf, digest) {
-        Ok(hash_value) => println!("{}: {}", hash_value, path_buf),
-        Err(e) => println!("{}: error during hashing: {}", path_buf, e),
+/// Returns the metadata of `path_buf`, or `None` for standard input.
+fn file_metadata(path_buf: &Utf8PathBuf) -> Option<std::fs::Metadata> {
+    if path_buf == STDIN_PATH {
+        return None;
     }
+    std::fs::metadata(path_buf).ok()
 }
 
-fn perform_hash(path_buf: &Utf8PathBuf, digest: &DigestType) -> std::io::Result<String> {
-    match digest {
-        DigestType::SHA256 => calculate_hash::<sha2::Sha256>(path_buf),
-        DigestType::SHA512 => calculate_hash::<sha2::Sha512>(path_buf),
+/// Calculates the hash values of `path_buf` for each of `digests`, reading it only once, or
+/// not at all if they are all in the cache.
+fn perform_hash(
+    path_buf: &Utf8PathBuf,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+) -> std::io::Result<Vec<Vec<u8>>> {
+    let result = match io_options.cache {
+        Some(cache) => {
+            let mut read = false;
+            let result = cache.get_or_hash(path_buf, digests, blake2, |digests| {
+                read = true;
+                hash_contents(path_buf, digests, blake2, io_options)
+            });
+            // A file found in the cache still counts as hashed, so that the ETA holds.
+            if !read
+                && let Some(progress) = io_options.progress
+                && let Some(metadata) = file_metadata(path_buf)
+            {
+                progress.add_bytes(metadata.len());
+            }
+            result
+        }
+        None => hash_contents(path_buf, digests, blake2, io_options),
+    };
+    if let Some(progress) = io_options.progress {
+        progress.add_file();
     }
+    result
 }
 
-fn calculate_hash<D: Digest + std::io::Write>(path_buf: &Utf8PathBuf) -> std::io::Result<String> {
-    let mut file = std::fs::File::open(path_buf)?;
-    let mut hasher = D::new();
-    let _n = io::copy(&mut file, &mut hasher)?;
-    let finalized_hash = hasher.finalize().to_vec();
-    Ok(to_hex_lowercase(&finalized_hash))
+/// Reads `path_buf` once and calculates its hash values for each of `digests`.
+fn hash_contents(
+    path_buf: &Utf8PathBuf,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+) -> std::io::Result<Vec<Vec<u8>>> {
+    let hashers = digests
+        .iter()
+        .map(|digest| new_hasher(digest, blake2))
+        .collect();
+    if let Some(mmap) = io_options.map(path_buf)? {
+        return Ok(calculate_hash_mapped(
+            &mmap,
+            digests,
+            hashers,
+            io_options.buffer_size,
+            io_options.progress,
+        ));
+    }
+    let mut reader = open_input(path_buf)?;
+    match io_options.progress {
+        Some(progress) => calculate_hash(
+            &mut ProgressReader::new(reader, progress),
+            hashers,
+            io_options.buffer_size,
+        ),
+        None => calculate_hash(&mut reader, hashers, io_options.buffer_size),
+    }
 }
 
-/// Converts a Vec<u8> into a lowercase hexadecimal string.
-///
-/// # Example
+/// Creates a [`Hasher`] that calculates `digest`.
+fn new_hasher(digest: &DigestType, blake2: &Blake2Params) -> Box<dyn Hasher> {
+    match digest {
+        DigestType::SHA224 => Box::new(sha2::Sha224::new()),
+        DigestType::SHA256 => Box::new(sha2::Sha256::new()),
+        DigestType::SHA384 => Box::new(sha2::Sha384::new()),
+        DigestType::SHA512 => Box::new(sha2::Sha512::new()),
+        DigestType::SHA512_224 => Box::new(sha2::Sha512_224::new()),
+        DigestType::SHA512_256 => Box::new(sha2::Sha512_256::new()),
+        DigestType::SHA3_224 => Box::new(sha3::Sha3_224::new()),
+        DigestType::SHA3_256 => Box::new(sha3::Sha3_256::new()),
+        DigestType::SHA3_384 => Box::new(sha3::Sha3_384::new()),
+        DigestType::SHA3_512 => Box::new(sha3::Sha3_512::new()),
+        DigestType::BLAKE2b | DigestType::BLAKE2s => blake2::new_hasher(digest, blake2),
+        DigestType::BLAKE3 => Box::new(<blake3::Hasher as Digest>::new()),
+        #[cfg(feature = "insecure")]
+        DigestType::MD5 => Box::new(md5::Md5::new()),
+        #[cfg(feature = "insecure")]
+        DigestType::SHA1 => Box::new(sha1::Sha1::new()),
+        #[cfg(feature = "insecure")]
+        DigestType::RIPEMD160 => Box::new(ripemd::Ripemd160::new()),
+    }
+}
+
+/// Mapped files of at least this many bytes are hashed on all cores when using BLAKE3 alone.
+/// Below this size, spawning work on the thread pool costs more than it gains.
+const BLAKE3_RAYON_THRESHOLD: usize = 1024 * 1024;
+
+/// When progress is reported, BLAKE3 hashes mapped files on all cores in parts of this many
+/// bytes, so that progress is seen before the whole file is done.
+const BLAKE3_RAYON_PROGRESS_CHUNK: usize = 64 * 1024 * 1024;
+
+/// Feeds `data`, the contents of a memory-mapped file, to all of `hashers` in chunks of
+/// `chunk_size` bytes, so that each chunk is still cached when the next hasher reads it.
+/// Each chunk is added to `progress`, if given.
 ///
-/// ```rust
-/// let vec_hash: Vec<u8> = vec![68, 201, 46];
-/// assert_eq!(to_hex_lowercase(&vec_hash, String::from("44c92e"));
-/// ```
-fn to_hex_lowercase(vec_hash: &[u8]) -> String {
-    vec_hash.iter().map(|b| format!("{:02x}", b)).collect()
+/// A hash value for BLAKE3 alone is instead calculated on all cores, using BLAKE3's tree mode.
+fn calculate_hash_mapped(
+    data: &[u8],
+    digests: &[DigestType],
+    mut hashers: Vec<Box<dyn Hasher>>,
+    chunk_size: usize,
+    progress: Option<&Progress>,
+) -> Vec<Vec<u8>> {
+    if digests == [DigestType::BLAKE3] && data.len() >= BLAKE3_RAYON_THRESHOLD {
+        let mut hasher = blake3::Hasher::new();
+        let rayon_chunk_size = progress.map_or(data.len(), |_| BLAKE3_RAYON_PROGRESS_CHUNK);
+        for chunk in data.chunks(rayon_chunk_size) {
+            hasher.update_rayon(chunk);
+            if let Some(progress) = progress {
+                progress.add_bytes(chunk.len() as u64);
+            }
+        }
+        return vec![blake3::Hasher::finalize(&hasher).as_bytes().to_vec()];
+    }
+    for chunk in data.chunks(chunk_size) {
+        for hasher in &mut hashers {
+            hasher.update(chunk);
+        }
+        if let Some(progress) = progress {
+            progress.add_bytes(chunk.len() as u64);
+        }
+    }
+    hashers.into_iter().map(|hasher| hasher.finish()).collect()
+}
+
+/// Opens `path_buf` for reading, or standard input if it is `-`.
+fn open_input(path_buf: &Utf8PathBuf) -> std::io::Result<Box<dyn Read>> {
+    if path_buf == STDIN_PATH {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(std::fs::File::open(path_buf)?))
+    }
+}
+
+/// A hash calculation in progress. Several of them can be fed from a single read of a file.
+trait Hasher {
+    /// Feeds `data` into the hash calculation.
+    fn update(&mut self, data: &[u8]);
+    /// Consumes the hasher and returns the hash value.
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+impl<D: Digest> Hasher for D {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().to_vec()
+    }
+}
+
+/// Reads `reader` to the end through a buffer of `buffer_size` bytes, feeding each read to all
+/// of `hashers`, and returns their hash values in the same order.
+fn calculate_hash<R: Read + ?Sized>(
+    reader: &mut R,
+    mut hashers: Vec<Box<dyn Hasher>>,
+    buffer_size: usize,
+) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for hasher in &mut hashers {
+            hasher.update(&buffer[..n]);
+        }
+    }
+    Ok(hashers.into_iter().map(|hasher| hasher.finish()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blake3_hashers() -> Vec<Box<dyn Hasher>> {
+        vec![new_hasher(&DigestType::BLAKE3, &Blake2Params::default())]
+    }
+
+    #[test]
+    fn blake3_on_all_cores_matches_streaming() {
+        // Long enough to be split into parts when progress is reported, with a partial last one.
+        let data: Vec<u8> = (0..BLAKE3_RAYON_PROGRESS_CHUNK + BLAKE3_RAYON_THRESHOLD + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for len in [
+            BLAKE3_RAYON_THRESHOLD,
+            3 * BLAKE3_RAYON_THRESHOLD + 1,
+            data.len(),
+        ] {
+            let data = &data[..len];
+            let streamed = calculate_hash(&mut &data[..], blake3_hashers(), 64 * 1024).unwrap();
+            let progress = Progress::default();
+            for progress in [None, Some(&progress)] {
+                let mapped = calculate_hash_mapped(
+                    data,
+                    &[DigestType::BLAKE3],
+                    blake3_hashers(),
+                    64 * 1024,
+                    progress,
+                );
+                assert_eq!(mapped, streamed, "{} bytes", len);
+            }
+        }
+    }
 }
@@ -0,0 +1,229 @@
+//! Serialization of files and directories in the Nix Archive (NAR) format, as hashed by
+//! `nix-hash` and used for Nix store paths.
+//!
+//! A NAR is a sequence of strings, each written as its length (u64, little-endian), its bytes
+//! and zero padding up to a multiple of 8 bytes:
+//!
+//! ```text
+//! nar       = "nix-archive-1" node
+//! node      = "(" "type" ( regular | symlink | directory ) ")"
+//! regular   = "regular" [ "executable" "" ] "contents" <contents>
+//! symlink   = "symlink" "target" <target>
+//! directory = "directory" { "entry" "(" "name" <name> "node" node ")" }
+//! ```
+//!
+//! Directory entries are sorted by the bytes of their names. Only the executable bit of a
+//! file's permissions is recorded, and timestamps and ownership are not recorded at all.
+
+use crate::blake2::Blake2Params;
+use crate::progress::{Progress, ProgressReader};
+use crate::{DigestType, Hasher, new_hasher};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::io::{self, Write};
+
+/// The magic string at the start of every NAR.
+const NAR_VERSION: &str = "nix-archive-1";
+
+/// Calculates the hash of the NAR serialization of `path` for each of `digests`, without
+/// holding the serialization in memory. The contents of regular files are counted in
+/// `progress`, if given.
+///
+/// # Errors
+///
+/// Returns an error if anything beneath `path` can't be read, isn't a regular file, directory
+/// or symbolic link, or has a name that isn't valid UTF-8.
+pub fn nar_hash(
+    path: &Utf8Path,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    progress: Option<&Progress>,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut writer = HashWriter(
+        digests
+            .iter()
+            .map(|digest| new_hasher(digest, blake2))
+            .collect(),
+    );
+    write_str(&mut writer, NAR_VERSION.as_bytes())?;
+    write_node(&mut writer, path, progress)?;
+    Ok(writer.0.into_iter().map(|hasher| hasher.finish()).collect())
+}
+
+/// Feeds everything written to it to each of its hashers.
+struct HashWriter(Vec<Box<dyn Hasher>>);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for hasher in &mut self.0 {
+            hasher.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the length of `bytes`, `bytes` themselves and the padding that follows them.
+fn write_str<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    write_padding(writer, bytes.len() as u64)
+}
+
+/// Writes the zeros that pad a string of `len` bytes to a multiple of 8 bytes.
+fn write_padding<W: Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    writer.write_all(&[0; 8][..padding as usize])
+}
+
+/// Serializes the file, symbolic link or directory at `path`.
+fn write_node<W: Write>(
+    writer: &mut W,
+    path: &Utf8Path,
+    progress: Option<&Progress>,
+) -> io::Result<()> {
+    let metadata = path.symlink_metadata()?;
+    let file_type = metadata.file_type();
+    write_str(writer, b"(")?;
+    write_str(writer, b"type")?;
+    if file_type.is_file() {
+        write_str(writer, b"regular")?;
+        if is_executable(&metadata) {
+            write_str(writer, b"executable")?;
+            write_str(writer, b"")?;
+        }
+        write_str(writer, b"contents")?;
+        write_contents(writer, path, metadata.len(), progress)?;
+    } else if file_type.is_symlink() {
+        write_str(writer, b"symlink")?;
+        write_str(writer, b"target")?;
+        write_str(writer, path.read_link_utf8()?.as_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        write_str(writer, b"directory")?;
+        let mut entries = path
+            .read_dir_utf8()?
+            .map(|entry| entry.map(|entry| entry.path().to_path_buf()))
+            .collect::<io::Result<Vec<Utf8PathBuf>>>()?;
+        entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        for entry in &entries {
+            write_str(writer, b"entry")?;
+            write_str(writer, b"(")?;
+            write_str(writer, b"name")?;
+            write_str(
+                writer,
+                entry
+                    .file_name()
+                    .expect("directory entries have a name")
+                    .as_bytes(),
+            )?;
+            write_str(writer, b"node")?;
+            write_node(writer, entry, progress)?;
+            write_str(writer, b")")?;
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: not a regular file, directory or symbolic link", path),
+        ));
+    }
+    write_str(writer, b")")
+}
+
+/// Writes the contents of the regular file at `path`, which is expected to be `len` bytes long.
+fn write_contents<W: Write>(
+    writer: &mut W,
+    path: &Utf8Path,
+    len: u64,
+    progress: Option<&Progress>,
+) -> io::Result<()> {
+    writer.write_all(&len.to_le_bytes())?;
+    let mut file = io::Read::take(std::fs::File::open(path)?, len);
+    let copied = match progress {
+        Some(progress) => {
+            let copied = io::copy(&mut ProgressReader::new(&mut file, progress), writer);
+            progress.add_file();
+            copied?
+        }
+        None => io::copy(&mut file, writer)?,
+    };
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{}: file changed size while reading", path),
+        ));
+    }
+    write_padding(writer, len)
+}
+
+/// Like Nix, only the owner's execute bit makes a file executable.
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{Encoding, to_hex_lowercase};
+
+    /// Creates an empty directory for a test, removing what an earlier run left behind.
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("digest-nar-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sha256_nar(path: &Utf8Path) -> Vec<u8> {
+        nar_hash(path, &[DigestType::SHA256], &Blake2Params::default(), None)
+            .unwrap()
+            .swap_remove(0)
+    }
+
+    #[test]
+    fn regular_file() {
+        let dir = test_dir("regular");
+        let file = dir.join("hello");
+        std::fs::write(&file, "hello\n").unwrap();
+        let hash_value = sha256_nar(&file);
+        assert_eq!(
+            to_hex_lowercase(&hash_value),
+            "1c37d01af40be2e80691de3cc3df44377a699afbb17c68f080964b2fd071fc13"
+        );
+        assert_eq!(
+            Encoding::NixBase32.encode(&DigestType::SHA256, &hash_value),
+            "04zwf782yjwnh3q6hz5izfd6jyip8kgw6g6yj43fiqhbyhdd0dqw"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_with_symlink_and_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("directory");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        // Created out of order, as entries must be sorted by name.
+        std::fs::write(root.join("x"), "").unwrap();
+        std::fs::set_permissions(root.join("x"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("a", root.join("b")).unwrap();
+        std::fs::write(root.join("a"), "abc").unwrap();
+        assert_eq!(
+            to_hex_lowercase(&sha256_nar(&root)),
+            "dfa274d9d5385339d7ebd5bb875abc258e5cca70eb7e3070c5ccfe55e69b174b"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
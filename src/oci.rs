@@ -0,0 +1,411 @@
+//! Verification of OCI image layout directories, as written by `skopeo copy oci:…`,
+//! `docker buildx --output type=oci` and similar tools.
+//!
+//! A layout contains an `oci-layout` marker file, an `index.json` image index and every blob
+//! under `blobs/<algorithm>/<encoded digest>`. Blobs are content-addressed, so verification
+//! consists of:
+//!
+//! 1. hashing every blob and comparing the result with its file name, and
+//! 2. following every descriptor from `index.json` through image indexes and manifests, and
+//!    comparing the declared digest and size with the blob it refers to.
+
+use crate::blake2::Blake2Params;
+use crate::encoding::to_hex_lowercase;
+use crate::input::IoOptions;
+use crate::{DigestType, perform_hash};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
+
+/// Media types whose `manifests` are descriptors of further manifests.
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Media types whose `config` and `layers` are descriptors of blobs.
+const MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// What was found when a blob was hashed.
+struct Blob {
+    size: u64,
+    /// True if the blob's content matches the digest in its file name.
+    verified: bool,
+}
+
+/// Verifies each of `layouts` and prints `OK` or `FAILED` for every blob and a line for every
+/// descriptor that doesn't match its blob.
+///
+/// Returns [`ExitCode::FAILURE`] if anything didn't match or couldn't be read.
+pub fn verify_layouts(layouts: &[Utf8PathBuf], io_options: &IoOptions) -> ExitCode {
+    let mut success = true;
+    for layout in layouts {
+        success &= verify_layout(layout, io_options);
+    }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn verify_layout(layout: &Utf8Path, io_options: &IoOptions) -> bool {
+    let marker = layout.join("oci-layout");
+    match read_json(&marker) {
+        Ok(value)
+            if value
+                .get("imageLayoutVersion")
+                .is_some_and(Value::is_string) => {}
+        Ok(_) => {
+            println!("{}: FAILED no imageLayoutVersion", marker);
+            return false;
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            println!("{}: FAILED open or read", marker);
+            return false;
+        }
+    }
+
+    let (blobs, mut success) = verify_blobs(layout, io_options);
+
+    let index_path = layout.join("index.json");
+    let index = match read_json(&index_path) {
+        Ok(index) => index,
+        Err(err) => {
+            eprintln!("{}", err);
+            println!("{}: FAILED open or read", index_path);
+            return false;
+        }
+    };
+    let mut visited = HashSet::new();
+    success &= verify_descriptors(
+        layout,
+        &index_path,
+        &index,
+        "manifests",
+        &blobs,
+        &mut visited,
+    );
+    success
+}
+
+/// Hashes every blob beneath `layout/blobs` and prints `OK` or `FAILED` for it.
+///
+/// Returns what was found, keyed by digest, and whether every blob matched.
+fn verify_blobs(layout: &Utf8Path, io_options: &IoOptions) -> (HashMap<String, Blob>, bool) {
+    let mut blobs = HashMap::new();
+    let mut success = true;
+    let blobs_dir = layout.join("blobs");
+    for (algorithm_dir, algorithm) in sorted_dir(&blobs_dir, &mut success) {
+        let Some(digest) = DigestType::value_variants()
+            .iter()
+            .find(|digest| digest.oci_name() == Some(algorithm.as_str()))
+        else {
+            eprintln!(
+                "WARNING: {}: skipping blobs of unsupported algorithm",
+                algorithm_dir
+            );
+            continue;
+        };
+        for (path, encoded) in sorted_dir(&algorithm_dir, &mut success) {
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    println!("{}: FAILED open or read", path);
+                    success = false;
+                    continue;
+                }
+            };
+            let verified = match perform_hash(
+                &path,
+                std::slice::from_ref(digest),
+                &Blake2Params::default(),
+                io_options,
+            ) {
+                Ok(mut hash_values) => to_hex_lowercase(&hash_values.swap_remove(0)) == encoded,
+                Err(e) => {
+                    eprintln!("{}: error during hashing: {}", path, e);
+                    println!("{}: FAILED open or read", path);
+                    success = false;
+                    continue;
+                }
+            };
+            if verified {
+                println!("{}: OK", path);
+            } else {
+                println!("{}: FAILED", path);
+                success = false;
+            }
+            blobs.insert(
+                format!("{}:{}", algorithm, encoded),
+                Blob { size, verified },
+            );
+        }
+    }
+    (blobs, success)
+}
+
+/// Checks the descriptors in the `key` field of `document`, which was read from `referrer`,
+/// against the blobs they refer to, and recurses into the image indexes and manifests among
+/// them.
+///
+/// Returns whether every descriptor matched.
+fn verify_descriptors(
+    layout: &Utf8Path,
+    referrer: &Utf8Path,
+    document: &Value,
+    key: &str,
+    blobs: &HashMap<String, Blob>,
+    visited: &mut HashSet<String>,
+) -> bool {
+    let descriptors = match document.get(key) {
+        Some(Value::Array(descriptors)) => descriptors.iter().collect(),
+        Some(descriptor @ Value::Object(_)) => vec![descriptor],
+        None => return true,
+        Some(_) => {
+            println!("{}: FAILED {} is not a descriptor", referrer, key);
+            return false;
+        }
+    };
+    let mut success = true;
+    for descriptor in descriptors {
+        let Some(digest) = descriptor.get("digest").and_then(Value::as_str) else {
+            println!("{}: FAILED descriptor without a digest", referrer);
+            success = false;
+            continue;
+        };
+        let Some(blob) = blobs.get(digest) else {
+            println!("{}: {}: FAILED blob is missing", referrer, digest);
+            success = false;
+            continue;
+        };
+        if !blob.verified {
+            println!(
+                "{}: {}: FAILED blob doesn't match its digest",
+                referrer, digest
+            );
+            success = false;
+            continue;
+        }
+        match descriptor.get("size").and_then(Value::as_u64) {
+            Some(size) if size == blob.size => {}
+            Some(size) => {
+                println!(
+                    "{}: {}: FAILED declared size {} but the blob has {} bytes",
+                    referrer, digest, size, blob.size
+                );
+                success = false;
+            }
+            None => {
+                println!("{}: {}: FAILED descriptor without a size", referrer, digest);
+                success = false;
+            }
+        }
+        // The blob's content matches the digest, so it is still worth following even if the
+        // declared size is wrong.
+        if !visited.insert(digest.to_string()) {
+            continue;
+        }
+        let media_type = descriptor.get("mediaType").and_then(Value::as_str);
+        if media_type.is_some_and(|media_type| {
+            !INDEX_MEDIA_TYPES.contains(&media_type) && !MANIFEST_MEDIA_TYPES.contains(&media_type)
+        }) {
+            continue;
+        }
+        let (algorithm, encoded) = digest.split_once(':').expect("blobs are keyed by digest");
+        let path = layout.join("blobs").join(algorithm).join(encoded);
+        let Ok(child) = read_json(&path) else {
+            // Only descriptors with a manifest media type must refer to JSON.
+            if media_type.is_some() {
+                println!("{}: FAILED not a JSON document", path);
+                success = false;
+            }
+            continue;
+        };
+        let child_media_type = media_type.or_else(|| child.get("mediaType")?.as_str());
+        if child_media_type.is_some_and(|media_type| INDEX_MEDIA_TYPES.contains(&media_type)) {
+            success &= verify_descriptors(layout, &path, &child, "manifests", blobs, visited);
+        } else if child_media_type
+            .is_some_and(|media_type| MANIFEST_MEDIA_TYPES.contains(&media_type))
+        {
+            for key in ["config", "layers"] {
+                success &= verify_descriptors(layout, &path, &child, key, blobs, visited);
+            }
+        }
+    }
+    success
+}
+
+/// Returns the entries of `dir` with their names, sorted by name.
+///
+/// Prints an error and clears `success` if `dir` can't be read.
+fn sorted_dir(dir: &Utf8Path, success: &mut bool) -> Vec<(Utf8PathBuf, String)> {
+    let entries = match dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{}: {}", dir, e);
+            println!("{}: FAILED open or read", dir);
+            *success = false;
+            return Vec::new();
+        }
+    };
+    let mut entries: Vec<(Utf8PathBuf, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| (entry.path().to_path_buf(), entry.file_name().to_string()))
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// Reads and parses the JSON document at `path`.
+fn read_json(path: &Utf8Path) -> Result<Value, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_slice(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::IoStrategy;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    const IO_OPTIONS: IoOptions = IoOptions {
+        strategy: IoStrategy::Auto,
+        buffer_size: 64 * 1024,
+        cache: None,
+        progress: None,
+    };
+
+    /// Creates an empty directory for a test, removing what an earlier run left behind.
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("digest-oci-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `contents` as a blob of `layout` and returns a descriptor of it.
+    fn write_blob(layout: &Utf8Path, media_type: &str, contents: &[u8]) -> Value {
+        let encoded = to_hex_lowercase(&Sha256::digest(contents));
+        std::fs::write(layout.join("blobs/sha256").join(&encoded), contents).unwrap();
+        json!({
+            "mediaType": media_type,
+            "digest": format!("sha256:{}", encoded),
+            "size": contents.len(),
+        })
+    }
+
+    /// Creates a layout in `layout` whose index refers to a manifest with a config and a
+    /// layer, and returns the descriptor of the layer.
+    fn create_layout(layout: &Utf8Path) -> Value {
+        std::fs::create_dir_all(layout.join("blobs/sha256")).unwrap();
+        std::fs::write(
+            layout.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .unwrap();
+        let config = write_blob(layout, "application/vnd.oci.image.config.v1+json", b"{}");
+        let layer = write_blob(layout, "application/vnd.oci.image.layer.v1.tar", b"layer");
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPES[0],
+            "config": config,
+            "layers": [layer],
+        });
+        let manifest = write_blob(
+            layout,
+            MANIFEST_MEDIA_TYPES[0],
+            manifest.to_string().as_bytes(),
+        );
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": INDEX_MEDIA_TYPES[0],
+            "manifests": [manifest],
+        });
+        std::fs::write(layout.join("index.json"), index.to_string()).unwrap();
+        layer
+    }
+
+    /// Returns the path of the blob `descriptor` refers to.
+    fn blob_path(layout: &Utf8Path, descriptor: &Value) -> Utf8PathBuf {
+        let digest = descriptor["digest"].as_str().unwrap();
+        layout.join("blobs").join(digest.replacen(':', "/", 1))
+    }
+
+    #[test]
+    fn valid_layout() {
+        let layout = test_dir("valid");
+        create_layout(&layout);
+        assert_eq!(
+            verify_layouts(std::slice::from_ref(&layout), &IO_OPTIONS),
+            ExitCode::SUCCESS
+        );
+        let (blobs, success) = verify_blobs(&layout, &IO_OPTIONS);
+        assert!(success);
+        assert_eq!(blobs.len(), 3);
+        assert!(blobs.values().all(|blob| blob.verified));
+        std::fs::remove_dir_all(layout).unwrap();
+    }
+
+    #[test]
+    fn tampered_blob() {
+        let layout = test_dir("tampered");
+        let layer = create_layout(&layout);
+        std::fs::write(blob_path(&layout, &layer), b"LAYER").unwrap();
+        assert_eq!(
+            verify_layouts(std::slice::from_ref(&layout), &IO_OPTIONS),
+            ExitCode::FAILURE
+        );
+        let (blobs, success) = verify_blobs(&layout, &IO_OPTIONS);
+        assert!(!success);
+        assert!(!blobs[layer["digest"].as_str().unwrap()].verified);
+        std::fs::remove_dir_all(layout).unwrap();
+    }
+
+    #[test]
+    fn missing_blob() {
+        let layout = test_dir("missing");
+        let layer = create_layout(&layout);
+        std::fs::remove_file(blob_path(&layout, &layer)).unwrap();
+        // Every remaining blob matches, so only the descriptor fails.
+        assert!(verify_blobs(&layout, &IO_OPTIONS).1);
+        assert!(!verify_layout(&layout, &IO_OPTIONS));
+        std::fs::remove_dir_all(layout).unwrap();
+    }
+
+    #[test]
+    fn descriptors() {
+        let layout = test_dir("descriptors");
+        let layer = create_layout(&layout);
+        let (blobs, _) = verify_blobs(&layout, &IO_OPTIONS);
+        let referrer = layout.join("manifest.json");
+        let verify = |document: &Value| {
+            let mut visited = HashSet::new();
+            verify_descriptors(&layout, &referrer, document, "layers", &blobs, &mut visited)
+        };
+
+        assert!(verify(&json!({ "layers": [layer] })));
+        assert!(verify(&json!({})));
+        let mut wrong_size = layer.clone();
+        wrong_size["size"] = json!(6);
+        assert!(!verify(&json!({ "layers": [wrong_size] })));
+        let mut without_size = layer.clone();
+        without_size.as_object_mut().unwrap().remove("size");
+        assert!(!verify(&json!({ "layers": [without_size] })));
+        let mut missing = layer.clone();
+        missing["digest"] = json!(format!("sha256:{}", "0".repeat(64)));
+        assert!(!verify(&json!({ "layers": [missing] })));
+        assert!(!verify(&json!({ "layers": "sha256:00" })));
+        std::fs::remove_dir_all(layout).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+//! Printing of hash values in the formats selectable with `--format`.
+
+use crate::DigestType;
+use crate::blake2::Blake2Params;
+use crate::encoding::{Encoding, to_multihash};
+use camino::Utf8Path;
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use std::borrow::Cow;
+use std::fs::Metadata;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The formats hash values can be printed in.
+#[derive(Debug, ValueEnum, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// One "<hash>: <path>" line per file and algorithm
+    Text,
+    /// One BSD-style "<ALGORITHM> (<path>) = <hash>" line per file and algorithm
+    Tag,
+    /// A JSON array with one object per file
+    Json,
+    /// One JSON object per file and line (newline-delimited JSON)
+    Ndjson,
+    /// Comma-separated values with a header row, quoted according to RFC 4180
+    Csv,
+    /// Tab-separated values with a header row; tabs, newlines, carriage returns and
+    /// backslashes in fields are escaped as \t, \n, \r and \\
+    Tsv,
+}
+
+/// The header row of `--format csv` and `--format tsv`.
+const TABLE_HEADER: [&str; 6] = ["path", "algorithm", "digest", "size", "modified", "error"];
+
+/// What became of one file, directory tree or NAR that was to be hashed.
+pub enum Outcome {
+    /// The hash values, in the same order as the algorithms.
+    Hashed(Vec<Vec<u8>>),
+    /// The path was rejected before hashing; see [`crate::CheckedFile::hashable`].
+    Unhashable(String),
+    /// Hashing started but failed.
+    Failed(io::Error),
+}
+
+/// Prints one [`Outcome`] after another in the selected format.
+pub struct Printer<'a> {
+    format: OutputFormat,
+    encoding: Encoding,
+    /// Whether hash values are wrapped in a multihash before they are encoded.
+    multihash: bool,
+    digests: &'a [DigestType],
+    blake2: &'a Blake2Params,
+    /// The number of records printed so far.
+    records: usize,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(
+        format: OutputFormat,
+        encoding: Encoding,
+        multihash: bool,
+        digests: &'a [DigestType],
+        blake2: &'a Blake2Params,
+    ) -> Self {
+        Printer {
+            format,
+            encoding,
+            multihash,
+            digests,
+            blake2,
+            records: 0,
+        }
+    }
+
+    /// Prints the outcome of hashing `path`, whose metadata is given if it is a file.
+    pub fn print(&mut self, path: &Utf8Path, metadata: Option<&Metadata>, outcome: &Outcome) {
+        let size = metadata.map(|metadata| metadata.len());
+        match self.format {
+            OutputFormat::Text | OutputFormat::Tag => self.print_text(path, outcome),
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                if self.records == 0 {
+                    self.print_row(&TABLE_HEADER.map(String::from));
+                }
+                self.print_table(path, metadata, outcome);
+            }
+            OutputFormat::Json => {
+                let separator = if self.records == 0 { "[" } else { ",\n" };
+                print!("{}{}", separator, self.to_json(path, size, outcome));
+            }
+            OutputFormat::Ndjson => println!("{}", self.to_json(path, size, outcome)),
+        }
+        self.records += 1;
+    }
+
+    /// Prints whatever must follow the last record.
+    pub fn finish(&self) {
+        match self.format {
+            OutputFormat::Json => println!("{}", if self.records == 0 { "[]" } else { "]" }),
+            OutputFormat::Csv | OutputFormat::Tsv if self.records == 0 => {
+                self.print_row(&TABLE_HEADER.map(String::from));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns `hash_value`, wrapped in a multihash if requested.
+    fn wrap<'b>(&self, digest: &DigestType, hash_value: &'b [u8]) -> Cow<'b, [u8]> {
+        if self.multihash {
+            Cow::Owned(to_multihash(digest, hash_value))
+        } else {
+            Cow::Borrowed(hash_value)
+        }
+    }
+
+    /// Encodes `hash_value` as text, wrapped in a multihash if requested.
+    fn encode(&self, digest: &DigestType, hash_value: &[u8]) -> String {
+        self.encoding.encode(digest, &self.wrap(digest, hash_value))
+    }
+
+    fn print_text(&self, path: &Utf8Path, outcome: &Outcome) {
+        match outcome {
+            Outcome::Hashed(hash_values) if self.encoding == Encoding::Raw => {
+                let mut stdout = io::stdout().lock();
+                for (digest, hash_value) in self.digests.iter().zip(hash_values) {
+                    if let Err(e) = stdout.write_all(&self.wrap(digest, hash_value)) {
+                        eprintln!("{}: error writing hash value: {}", path, e);
+                    }
+                }
+            }
+            Outcome::Hashed(hash_values) => {
+                for (digest, hash_value) in self.digests.iter().zip(hash_values) {
+                    let hash_value = self.encode(digest, hash_value);
+                    if self.format == OutputFormat::Tag {
+                        println!("{} ({}) = {}", digest.label(self.blake2), path, hash_value);
+                    } else {
+                        println!("{}: {}", hash_value, path);
+                    }
+                }
+            }
+            Outcome::Unhashable(err) => eprintln!("{}: unable to hash this file", err),
+            // Keeps raw hash values on stdout from being interleaved with error messages.
+            Outcome::Failed(e) if self.encoding == Encoding::Raw => {
+                eprintln!("{}: error during hashing: {}", path, e)
+            }
+            Outcome::Failed(e) => println!("{}: error during hashing: {}", path, e),
+        }
+    }
+
+    /// Builds the JSON object for one record, e.g.
+    ///
+    /// ```json
+    /// {"path":"a","size":6,"digests":[{"algorithm":"SHA256","digest":"5891…"}],"error":null}
+    /// ```
+    ///
+    /// `digests` is empty and `error` is a message if the file couldn't be hashed.
+    fn to_json(&self, path: &Utf8Path, size: Option<u64>, outcome: &Outcome) -> Value {
+        let (digests, error) = match outcome {
+            Outcome::Hashed(hash_values) => (
+                self.digests
+                    .iter()
+                    .zip(hash_values)
+                    .map(|(digest, hash_value)| {
+                        json!({
+                            "algorithm": digest.label(self.blake2),
+                            "digest": self.encode(digest, hash_value),
+                        })
+                    })
+                    .collect(),
+                None,
+            ),
+            Outcome::Unhashable(err) => (Vec::new(), Some(err.clone())),
+            Outcome::Failed(e) => (Vec::new(), Some(e.to_string())),
+        };
+        json!({
+            "path": path.as_str(),
+            "size": size,
+            "digests": digests,
+            "error": error,
+        })
+    }
+
+    /// Prints one row per algorithm, or a single row with an error.
+    fn print_table(&self, path: &Utf8Path, metadata: Option<&Metadata>, outcome: &Outcome) {
+        let size = metadata.map_or(String::new(), |metadata| metadata.len().to_string());
+        let modified = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .map_or(String::new(), format_timestamp);
+        match outcome {
+            Outcome::Hashed(hash_values) => {
+                for (digest, hash_value) in self.digests.iter().zip(hash_values) {
+                    self.print_row(&[
+                        path.to_string(),
+                        digest.label(self.blake2),
+                        self.encode(digest, hash_value),
+                        size.clone(),
+                        modified.clone(),
+                        String::new(),
+                    ]);
+                }
+            }
+            Outcome::Unhashable(err) => self.print_row(&[
+                path.to_string(),
+                String::new(),
+                String::new(),
+                size,
+                modified,
+                err.clone(),
+            ]),
+            Outcome::Failed(e) => self.print_row(&[
+                path.to_string(),
+                String::new(),
+                String::new(),
+                size,
+                modified,
+                e.to_string(),
+            ]),
+        }
+    }
+
+    /// Prints `fields` as one CSV or TSV row.
+    fn print_row(&self, fields: &[String]) {
+        if self.format == OutputFormat::Csv {
+            let fields: Vec<String> = fields.iter().map(|field| quote_csv(field)).collect();
+            print!("{}\r\n", fields.join(","));
+        } else {
+            let fields: Vec<String> = fields.iter().map(|field| escape_tsv(field)).collect();
+            println!("{}", fields.join("\t"));
+        }
+    }
+}
+
+/// Quotes a CSV field according to RFC 4180 if it contains a comma, quote or line break.
+fn quote_csv(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Escapes the characters that can't appear literally in a TSV field.
+fn escape_tsv(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats `time` as an RFC 3339 timestamp in UTC with second precision, e.g.
+/// `2024-05-01T13:37:00Z`.
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    };
+    let (days, seconds_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    // Converts days since 1970-01-01 to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(quote_csv("a b"), "a b");
+        assert_eq!(quote_csv("a,b"), "\"a,b\"");
+        assert_eq!(quote_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_csv("a\r\nb"), "\"a\r\nb\"");
+    }
+
+    #[test]
+    fn escapes_tsv_fields() {
+        assert_eq!(escape_tsv("a b"), "a b");
+        assert_eq!(escape_tsv("a\tb\nc\rd\\e"), "a\\tb\\nc\\rd\\\\e");
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        let at = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(at(1_709_213_825)), "2024-02-29T13:37:05Z");
+        assert_eq!(format_timestamp(at(4_107_542_400)), "2100-03-01T00:00:00Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH - Duration::from_millis(500)),
+            "1969-12-31T23:59:59Z"
+        );
+    }
+}
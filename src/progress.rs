@@ -0,0 +1,354 @@
+//! Progress reporting while files are hashed, as a status line on a terminal and as a stream
+//! of JSON records for other programs.
+//!
+//! Hashing threads only add to atomic counters. A reporter thread reads them once a second, so
+//! reporting costs nothing per read.
+//!
+//! Each record of the stream is a JSON object on a line of its own:
+//!
+//! ```json
+//! {"bytes":1048576,"total_bytes":4194304,"files":1,"total_files":4,"elapsed_seconds":0.5,"bytes_per_second":2097152,"eta_seconds":1.5,"done":false}
+//! ```
+//!
+//! `total_bytes`, `total_files` and `eta_seconds` are `null` when the amount of input isn't
+//! known in advance, e.g. with `--check` or for standard input. The last record has `done` set.
+
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often progress is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The amount of input hashed so far.
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    bytes: AtomicU64,
+    files: AtomicUsize,
+    /// The number of files and bytes to be hashed, once known.
+    total: OnceLock<(usize, Option<u64>)>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            files: AtomicUsize::new(0),
+            total: OnceLock::new(),
+        }
+    }
+}
+
+impl Progress {
+    /// Records that `bytes` more bytes were hashed.
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records that another file was hashed completely, or failed.
+    pub fn add_file(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the number of files to be hashed and their total size, or `None` if the size
+    /// isn't known, which allows an ETA to be estimated.
+    pub fn set_total(&self, files: usize, bytes: Option<u64>) {
+        let _ = self.total.set((files, bytes));
+    }
+
+    /// Takes a snapshot of the counters.
+    fn snapshot(&self) -> Snapshot {
+        let (total_files, total_bytes) = match self.total.get() {
+            Some(&(files, bytes)) => (Some(files), bytes),
+            None => (None, None),
+        };
+        Snapshot {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            total_bytes,
+            files: self.files.load(Ordering::Relaxed),
+            total_files,
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+/// A reader that adds the number of bytes read to a [`Progress`].
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a Progress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.add_bytes(n as u64);
+        Ok(n)
+    }
+}
+
+/// The counters of a [`Progress`] at one point in time.
+struct Snapshot {
+    bytes: u64,
+    total_bytes: Option<u64>,
+    files: usize,
+    total_files: Option<usize>,
+    elapsed: Duration,
+}
+
+impl Snapshot {
+    fn bytes_per_second(&self) -> u64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            (self.bytes as f64 / seconds) as u64
+        } else {
+            0
+        }
+    }
+
+    /// Estimates the time remaining from the average throughput so far.
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.total_bytes?.saturating_sub(self.bytes);
+        let rate = self.bytes_per_second();
+        (rate > 0).then(|| Duration::from_secs_f64(remaining as f64 / rate as f64))
+    }
+
+    /// Formats the snapshot as a status line, e.g.
+    /// `1/4 files, 1.0 MiB of 4.0 MiB (25%), 2.0 MiB/s, ETA 2s`.
+    fn status_line(&self) -> String {
+        let mut line = match self.total_files {
+            Some(total_files) => format!("{}/{} files", self.files, total_files),
+            None => format!("{} files", self.files),
+        };
+        line.push_str(&format!(", {}", format_bytes(self.bytes)));
+        if let Some(total_bytes) = self.total_bytes {
+            let percent = match total_bytes {
+                0 => 100,
+                total_bytes => (self.bytes.min(total_bytes) * 100 / total_bytes) as u8,
+            };
+            line.push_str(&format!(" of {} ({}%)", format_bytes(total_bytes), percent));
+        }
+        line.push_str(&format!(", {}/s", format_bytes(self.bytes_per_second())));
+        if let Some(eta) = self.eta() {
+            line.push_str(&format!(", ETA {}", format_duration(eta)));
+        }
+        line
+    }
+
+    /// Formats the snapshot as a record of the progress stream.
+    fn record(&self, done: bool) -> String {
+        json!({
+            "bytes": self.bytes,
+            "total_bytes": self.total_bytes,
+            "files": self.files,
+            "total_files": self.total_files,
+            "elapsed_seconds": self.elapsed.as_secs_f64(),
+            "bytes_per_second": self.bytes_per_second(),
+            "eta_seconds": self.eta().map(|eta| eta.as_secs_f64()),
+            "done": done,
+        })
+        .to_string()
+    }
+}
+
+/// Formats a number of bytes with a binary unit, e.g. `1.5 GiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a duration to the second, e.g. `1h 02m 03s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+/// A thread reporting a [`Progress`] once a second until it is dropped, which reports the
+/// final state.
+#[derive(Debug)]
+pub struct Reporter {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    /// Starts reporting `progress` on stderr if `status_line` is set and stderr is a terminal,
+    /// and to `stream` if it is given.
+    ///
+    /// Returns `None` if there is nowhere to report to.
+    pub fn start(
+        progress: Arc<Progress>,
+        status_line: bool,
+        stream: Option<File>,
+    ) -> Option<Reporter> {
+        let status_line = status_line && io::stderr().is_terminal();
+        if !status_line && stream.is_none() {
+            return None;
+        }
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut stream = stream;
+            loop {
+                let done = match stopped.recv_timeout(REPORT_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                };
+                let snapshot = progress.snapshot();
+                if status_line {
+                    // The line is redrawn in place, and left standing once hashing is done.
+                    eprint!(
+                        "\r{}\x1b[K{}",
+                        snapshot.status_line(),
+                        if done { "\n" } else { "" }
+                    );
+                }
+                if let Some(file) = &mut stream
+                    && let Err(e) =
+                        file.write_all(format!("{}\n", snapshot.record(done)).as_bytes())
+                {
+                    eprintln!("WARNING: unable to write progress: {}", e);
+                    stream = None;
+                }
+                if done {
+                    break;
+                }
+            }
+        });
+        Some(Reporter {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 512 * MIB), "1.5 GiB");
+        assert_eq!(format_bytes(u64::MAX), "16384.0 PiB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(62)), "1m 02s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
+    }
+
+    #[test]
+    fn known_total() {
+        let snapshot = Snapshot {
+            bytes: MIB,
+            total_bytes: Some(4 * MIB),
+            files: 1,
+            total_files: Some(4),
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(snapshot.eta(), Some(Duration::from_secs(6)));
+        assert_eq!(
+            snapshot.status_line(),
+            "1/4 files, 1.0 MiB of 4.0 MiB (25%), 512.0 KiB/s, ETA 6s"
+        );
+        assert_eq!(
+            snapshot.record(false),
+            r#"{"bytes":1048576,"total_bytes":4194304,"files":1,"total_files":4,"elapsed_seconds":2.0,"bytes_per_second":524288,"eta_seconds":6.0,"done":false}"#
+        );
+    }
+
+    #[test]
+    fn unknown_total() {
+        let snapshot = Snapshot {
+            bytes: MIB,
+            total_bytes: None,
+            files: 3,
+            total_files: None,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(snapshot.eta(), None);
+        assert_eq!(snapshot.status_line(), "3 files, 1.0 MiB, 512.0 KiB/s");
+        assert_eq!(
+            snapshot.record(true),
+            r#"{"bytes":1048576,"total_bytes":null,"files":3,"total_files":null,"elapsed_seconds":2.0,"bytes_per_second":524288,"eta_seconds":null,"done":true}"#
+        );
+    }
+
+    #[test]
+    fn empty_total() {
+        // With only empty files there is nothing left to read, and no throughput to estimate from.
+        let snapshot = Snapshot {
+            bytes: 0,
+            total_bytes: Some(0),
+            files: 1,
+            total_files: Some(2),
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(snapshot.eta(), None);
+        assert_eq!(
+            snapshot.status_line(),
+            "1/2 files, 0 B of 0 B (100%), 0 B/s"
+        );
+        assert_eq!(
+            snapshot.record(false),
+            r#"{"bytes":0,"total_bytes":0,"files":1,"total_files":2,"elapsed_seconds":0.0,"bytes_per_second":0,"eta_seconds":null,"done":false}"#
+        );
+    }
+
+    #[test]
+    fn counters() {
+        let progress = Progress::default();
+        let mut reader = ProgressReader::new(&b"abcde"[..], &progress);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        progress.add_file();
+        progress.set_total(2, Some(10));
+        progress.set_total(3, None);
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.bytes, 5);
+        assert_eq!(snapshot.files, 1);
+        assert_eq!(snapshot.total_bytes, Some(10));
+        assert_eq!(snapshot.total_files, Some(2));
+    }
+}
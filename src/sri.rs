@@ -0,0 +1,170 @@
+//! Verification of files against Subresource Integrity metadata, as found in `integrity`
+//! attributes of HTML elements and in `package-lock.json`.
+
+use crate::blake2::Blake2Params;
+use crate::input::IoOptions;
+use crate::{CheckedFile, DigestType, perform_hash};
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+/// The hash values of the strongest algorithm in an SRI string, e.g.
+/// `sha256-<base64> sha512-<base64>`.
+#[derive(Debug, Clone)]
+pub struct Integrity {
+    /// The strongest algorithm the string contains a hash value for.
+    digest: DigestType,
+    /// The hash values given for `digest`; a file matches if it matches any of them.
+    hash_values: Vec<Vec<u8>>,
+    /// Tokens with an unknown algorithm or malformed hash value, which are ignored.
+    ignored: Vec<String>,
+}
+
+/// The algorithms usable in SRI strings, from weakest to strongest.
+const STRENGTH: [DigestType; 3] = [DigestType::SHA256, DigestType::SHA384, DigestType::SHA512];
+
+impl FromStr for Integrity {
+    type Err = String;
+
+    /// Parses whitespace-separated `<algorithm>-<base64>[?<options>]` tokens.
+    ///
+    /// As in browsers, tokens with an unknown algorithm or malformed hash value are ignored and
+    /// only the strongest remaining algorithm is used. [`verify_files`] warns about them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hashes: Vec<(DigestType, Vec<u8>)> = Vec::new();
+        let mut ignored = Vec::new();
+        for token in s.split_ascii_whitespace() {
+            let token = token
+                .split_once('?')
+                .map_or(token, |(token, _options)| token);
+            let parsed = token.split_once('-').and_then(|(name, hash_value)| {
+                let digest = DigestType::value_variants()
+                    .iter()
+                    .find(|digest| digest.sri_name() == Some(name))?;
+                let hash_value = data_encoding::BASE64.decode(hash_value.as_bytes()).ok()?;
+                (hash_value.len() == digest.output_size(&Blake2Params::default()))
+                    .then(|| (digest.clone(), hash_value))
+            });
+            match parsed {
+                Some(hash) => hashes.push(hash),
+                None => ignored.push(token.to_string()),
+            }
+        }
+        let digest = hashes
+            .iter()
+            .map(|(digest, _)| digest)
+            .max_by_key(|digest| STRENGTH.iter().position(|strength| strength == *digest))
+            .ok_or_else(|| {
+                String::from("expected sha256-, sha384- or sha512- followed by a base64 hash value")
+            })?
+            .clone();
+        Ok(Integrity {
+            hash_values: hashes
+                .into_iter()
+                .filter(|(hash_digest, _)| *hash_digest == digest)
+                .map(|(_, hash_value)| hash_value)
+                .collect(),
+            digest,
+            ignored,
+        })
+    }
+}
+
+/// Checks each of `files` against `integrity` and prints `OK` or `FAILED` for it, after
+/// warning about the values of `integrity` that were ignored.
+///
+/// Returns [`ExitCode::FAILURE`] if any file did not match or could not be read.
+pub fn verify_files(
+    files: &[Utf8PathBuf],
+    integrity: &Integrity,
+    io_options: &IoOptions,
+) -> ExitCode {
+    for token in &integrity.ignored {
+        eprintln!("WARNING: ignoring unsupported integrity value {:?}", token);
+    }
+    let mut success = true;
+    for file in files {
+        let CheckedFile {
+            file_path: path_buf,
+            hashable: result,
+        } = CheckedFile::new(file);
+        if let Err(err) = result {
+            eprintln!("{}: unable to hash this file", err);
+            println!("{}: FAILED open or read", path_buf);
+            success = false;
+            continue;
+        }
+        let hash_value = perform_hash(
+            &path_buf,
+            std::slice::from_ref(&integrity.digest),
+            &Blake2Params::default(),
+            io_options,
+        )
+        .map(|mut hash_values| hash_values.swap_remove(0));
+        match hash_value {
+            Ok(hash_value) if integrity.hash_values.contains(&hash_value) => {
+                println!("{}: OK", path_buf);
+            }
+            Ok(_) => {
+                println!("{}: FAILED", path_buf);
+                success = false;
+            }
+            Err(e) => {
+                eprintln!("{}: error during hashing: {}", path_buf, e);
+                println!("{}: FAILED open or read", path_buf);
+                success = false;
+            }
+        }
+    }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_ABC: &str = "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+    const SHA384_ABC: &str =
+        "sha384-ywB1P0WjXou1oD1pmsZQBycsMqsO3tFjGotgWkP/W+2AhgcroefMI1i67KE0yCWn";
+
+    #[test]
+    fn parses_a_single_value() {
+        let integrity: Integrity = SHA256_ABC.parse().unwrap();
+        assert_eq!(integrity.digest, DigestType::SHA256);
+        assert_eq!(
+            integrity.hash_values,
+            [data_encoding::HEXLOWER
+                .decode(b"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+                .unwrap()]
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_strongest_algorithm() {
+        let integrity: Integrity = format!("{} {}?foo {}", SHA384_ABC, SHA256_ABC, SHA384_ABC)
+            .parse()
+            .unwrap();
+        assert_eq!(integrity.digest, DigestType::SHA384);
+        assert_eq!(integrity.hash_values.len(), 2);
+        // Values of weaker algorithms are supported, just not used.
+        assert!(integrity.ignored.is_empty());
+    }
+
+    #[test]
+    fn ignores_unsupported_values() {
+        let integrity: Integrity = format!("md5-kAFQmDzST7DWlj99KOF/cg== {}", SHA256_ABC)
+            .parse()
+            .unwrap();
+        assert_eq!(integrity.digest, DigestType::SHA256);
+        assert_eq!(integrity.ignored, ["md5-kAFQmDzST7DWlj99KOF/cg=="]);
+        // A SHA-256 value of the wrong length.
+        assert!("sha256-AAAA".parse::<Integrity>().is_err());
+        assert!("".parse::<Integrity>().is_err());
+        assert!("sha256-not base64!".parse::<Integrity>().is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! A single digest over a whole directory, calculated as a Merkle tree.
+//!
+//! # Canonical encoding, version 1
+//!
+//! The digest of a directory is the hash of the byte string
+//!
+//! ```text
+//! "digest-tree-v1\0" || entry || entry || ...
+//! ```
+//!
+//! with one `entry` per child, sorted by the bytes of its UTF-8 name:
+//!
+//! ```text
+//! kind (1 byte) || name length (u64, big-endian) || name || digest length (u64, big-endian) || digest
+//! ```
+//!
+//! | `kind` | Child                      | `digest`                                   |
+//! |--------|----------------------------|--------------------------------------------|
+//! | `d`    | directory                  | digest of the directory, recursively       |
+//! | `f`    | regular file               | hash of its contents                       |
+//! | `x`    | regular file, executable   | hash of its contents                       |
+//! | `l`    | symbolic link (not followed) | hash of its target path as UTF-8         |
+//!
+//! A file counts as executable if any of its execute permission bits is set; on platforms
+//! without them no file is executable. Every hash uses the same algorithm, the names of the
+//! root directory and its parents are not part of the encoding, and empty directories are. Any
+//! other kind of file, or a name that isn't valid UTF-8, makes the digest fail rather than
+//! silently differ between machines.
+//!
+//! Any change to this encoding must use a new version string.
+
+use crate::blake2::Blake2Params;
+use crate::input::IoOptions;
+use crate::walk::{self, WalkOptions};
+use crate::{DigestType, new_hasher, perform_hash};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeMap;
+use std::io;
+
+/// The prefix of every directory encoding, which identifies the version of the encoding.
+const TREE_VERSION: &[u8] = b"digest-tree-v1\0";
+
+/// A file or directory beneath the root of a tree.
+#[derive(Debug)]
+enum Node {
+    /// A regular file and whether it is executable.
+    File { path: Utf8PathBuf, executable: bool },
+    /// A symbolic link and the path it points to.
+    Symlink { target: String },
+    /// A directory and its children by name.
+    Directory(BTreeMap<String, Node>),
+}
+
+impl Node {
+    /// Returns the `kind` byte of the canonical encoding.
+    fn kind(&self) -> u8 {
+        match self {
+            Node::File {
+                executable: false, ..
+            } => b'f',
+            Node::File {
+                executable: true, ..
+            } => b'x',
+            Node::Symlink { .. } => b'l',
+            Node::Directory(_) => b'd',
+        }
+    }
+
+    /// Calculates the digest of this node for each of `digests`.
+    fn digest(
+        &self,
+        digests: &[DigestType],
+        blake2: &Blake2Params,
+        io_options: &IoOptions,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        match self {
+            Node::File { path, .. } => perform_hash(path, digests, blake2, io_options),
+            Node::Symlink { target } => Ok(hash_bytes(digests, blake2, &[target.as_bytes()])),
+            Node::Directory(children) => {
+                let mut hashers: Vec<_> = digests
+                    .iter()
+                    .map(|digest| new_hasher(digest, blake2))
+                    .collect();
+                for hasher in &mut hashers {
+                    hasher.update(TREE_VERSION);
+                }
+                for (name, child) in children {
+                    let child_digests = child.digest(digests, blake2, io_options)?;
+                    for (hasher, child_digest) in hashers.iter_mut().zip(&child_digests) {
+                        hasher.update(&[child.kind()]);
+                        hasher.update(&(name.len() as u64).to_be_bytes());
+                        hasher.update(name.as_bytes());
+                        hasher.update(&(child_digest.len() as u64).to_be_bytes());
+                        hasher.update(child_digest);
+                    }
+                }
+                Ok(hashers.into_iter().map(|hasher| hasher.finish()).collect())
+            }
+        }
+    }
+}
+
+/// Hashes the concatenation of `parts` with each of `digests`.
+fn hash_bytes(digests: &[DigestType], blake2: &Blake2Params, parts: &[&[u8]]) -> Vec<Vec<u8>> {
+    digests
+        .iter()
+        .map(|digest| {
+            let mut hasher = new_hasher(digest, blake2);
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Calculates the tree digest of the directory `root` for each of `digests`, considering only
+/// the entries that pass `options`.
+///
+/// # Errors
+///
+/// Returns an error if `root` is not a directory, if any entry can't be read, or if an entry
+/// can't be represented in the canonical encoding.
+pub fn tree_digest(
+    root: &Utf8Path,
+    options: &WalkOptions,
+    digests: &[DigestType],
+    blake2: &Blake2Params,
+    io_options: &IoOptions,
+) -> io::Result<Vec<Vec<u8>>> {
+    if !root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a directory",
+        ));
+    }
+    let mut tree = BTreeMap::new();
+    for entry in walk::walker(root, options) {
+        let entry = entry.map_err(io::Error::other)?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let path = Utf8Path::from_path(entry.path()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: path is not valid UTF-8", entry.path().display()),
+            )
+        })?;
+        let relative = path
+            .strip_prefix(root)
+            .expect("walked entries are beneath the root");
+        insert(&mut tree, relative, new_node(path)?);
+    }
+    Node::Directory(tree).digest(digests, blake2, io_options)
+}
+
+/// Creates the node for `path` without following symbolic links.
+fn new_node(path: &Utf8Path) -> io::Result<Node> {
+    let metadata = path.symlink_metadata()?;
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        Ok(Node::Directory(BTreeMap::new()))
+    } else if file_type.is_file() {
+        Ok(Node::File {
+            path: path.to_path_buf(),
+            executable: is_executable(&metadata),
+        })
+    } else if file_type.is_symlink() {
+        let target = path.read_link_utf8()?;
+        Ok(Node::Symlink {
+            target: target.into_string(),
+        })
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: not a regular file, directory or symbolic link", path),
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Inserts `node` into `tree` at `relative`, whose parent directories were walked before it.
+fn insert(tree: &mut BTreeMap<String, Node>, relative: &Utf8Path, node: Node) {
+    let mut components = relative.components().map(|c| c.as_str().to_string());
+    let name = components
+        .next_back()
+        .expect("walked entries beneath the root have a name");
+    let mut children = tree;
+    for component in components {
+        children = match children.get_mut(&component) {
+            Some(Node::Directory(grandchildren)) => grandchildren,
+            _ => unreachable!("parent directories are walked before their children"),
+        };
+    }
+    children.insert(name, node);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::to_hex_lowercase;
+    use crate::input::IoStrategy;
+
+    /// Creates an empty directory for a test, removing what an earlier run left behind.
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("digest-tree-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates a tree with a file, a nested directory, an empty directory, an executable file
+    /// and a symbolic link in `dir`.
+    #[cfg(unix)]
+    fn create_tree(dir: &Utf8Path) {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::write(dir.join("a"), "abc").unwrap();
+        std::fs::create_dir(dir.join("empty")).unwrap();
+        std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+        std::fs::write(dir.join("run"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(dir.join("run"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/b"), "hi\n").unwrap();
+    }
+
+    fn sha256_tree(root: &Utf8Path, options: &WalkOptions) -> io::Result<String> {
+        let io_options = IoOptions {
+            strategy: IoStrategy::Auto,
+            buffer_size: 64 * 1024,
+            cache: None,
+            progress: None,
+        };
+        let hash_values = tree_digest(
+            root,
+            options,
+            &[DigestType::SHA256],
+            &Blake2Params::default(),
+            &io_options,
+        )?;
+        Ok(to_hex_lowercase(&hash_values[0]))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn known_answer() {
+        let dir = test_dir("known-answer");
+        create_tree(&dir);
+        assert_eq!(
+            sha256_tree(&dir, &WalkOptions::default()).unwrap(),
+            "3c6a80e494e2f868b5021a44e3d74ad5be868a9440dbc00b965875c29ba565f7"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filters() {
+        let dir = test_dir("filters");
+        create_tree(&dir);
+        let excluded = WalkOptions {
+            exclude: vec!["sub/".to_string()],
+            ..WalkOptions::default()
+        };
+        assert_eq!(
+            sha256_tree(&dir, &excluded).unwrap(),
+            "09cbe50b38a6fc8367d7541d79432e1c7da46af41526ace36fc28a9085bdd70a"
+        );
+        let included = WalkOptions {
+            include: vec!["b".to_string()],
+            ..WalkOptions::default()
+        };
+        // Directories don't have to match, so only `a`, `link` and `run` are left out.
+        assert_eq!(
+            sha256_tree(&dir, &included).unwrap(),
+            "301a6d8feccd243f6d922d625a7352c7aa824d96a13ba7016f77dfed8f4da1e3"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let dir = test_dir("not-a-directory");
+        std::fs::write(dir.join("a"), "abc").unwrap();
+        let err = sha256_tree(&dir.join("a"), &WalkOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
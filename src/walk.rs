@@ -0,0 +1,243 @@
+//! Expansion of directories given on the command line into the files beneath them.
+
+use crate::CheckedFile;
+use camino::{Utf8Path, Utf8PathBuf};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::{Walk, WalkBuilder};
+use std::path::Path;
+
+/// Options controlling which files beneath a directory are hashed.
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    /// Globs of files to hash; if any are given, files matching none of them are skipped.
+    pub include: Vec<String>,
+    /// Globs of files and directories to skip. These take precedence over `include`.
+    pub exclude: Vec<String>,
+    /// Skip files ignored by `.gitignore`, `.ignore` and git's global and repository excludes.
+    pub respect_gitignore: bool,
+}
+
+impl WalkOptions {
+    /// Checks that all globs are valid.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first invalid glob.
+    pub fn validate(&self) -> Result<(), String> {
+        self.overrides(Utf8Path::new(".")).map(|_| ())
+    }
+
+    /// Builds the glob matcher for a walk starting at `root`.
+    ///
+    /// Globs use `.gitignore` syntax and are matched against paths relative to `root`, so
+    /// `target/` skips every directory named `target` and `/target/` only the top-level one.
+    fn overrides(&self, root: &Utf8Path) -> Result<Override, String> {
+        let mut builder = OverrideBuilder::new(root);
+        for glob in &self.include {
+            builder.add(glob).map_err(|e| e.to_string())?;
+        }
+        for glob in &self.exclude {
+            builder
+                .add(&format!("!{}", glob))
+                .map_err(|e| e.to_string())?;
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+/// Returns every regular file beneath `root` that passes `options`, sorted by path so the
+/// output is deterministic.
+///
+/// Each path starts with `root` exactly as it was given, so that the output can be checked
+/// from the same working directory. Symbolic links to regular files are included, but
+/// symbolic links to directories are not descended into. Entries that cannot be read become
+/// a [`CheckedFile`] carrying the error.
+pub fn walk_dir(root: &Utf8Path, options: &WalkOptions) -> Vec<CheckedFile> {
+    walker(root, options)
+        .filter_map(|entry| match entry {
+            Ok(entry) if entry.path().is_file() => Some(checked_entry(entry.into_path())),
+            Ok(_) => None,
+            Err(e) => Some(CheckedFile {
+                file_path: error_path(&e).map_or_else(
+                    || root.to_path_buf(),
+                    |path| Utf8PathBuf::from(path.to_string_lossy().into_owned()),
+                ),
+                hashable: Err(format!("{}", e)),
+            }),
+        })
+        .collect()
+}
+
+/// Returns the path of the entry a walk error occurred at, if it is known.
+fn error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// Returns an iterator over `root` and everything beneath it that passes `options`, in
+/// depth-first order with the entries of each directory sorted by name. Symbolic links are
+/// not followed.
+pub fn walker(root: &Utf8Path, options: &WalkOptions) -> Walk {
+    let overrides = options
+        .overrides(root)
+        .expect("globs are validated before walking");
+    WalkBuilder::new(root)
+        .standard_filters(false)
+        .git_ignore(options.respect_gitignore)
+        .git_global(options.respect_gitignore)
+        .git_exclude(options.respect_gitignore)
+        .ignore(options.respect_gitignore)
+        .parents(options.respect_gitignore)
+        .require_git(false)
+        .overrides(overrides)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build()
+}
+
+/// Converts a path found while walking a directory into a [`CheckedFile`].
+fn checked_entry(path: std::path::PathBuf) -> CheckedFile {
+    match Utf8PathBuf::from_path_buf(path) {
+        Ok(path) => CheckedFile::new(&path),
+        Err(path) => CheckedFile {
+            file_path: Utf8PathBuf::from(path.to_string_lossy().into_owned()),
+            hashable: Err(format!("{}: path is not valid UTF-8", path.display())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test, removing what an earlier run left behind.
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("digest-walk-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates each of `files`, and their parent directories, beneath `root`.
+    fn create_files(root: &Utf8Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+    }
+
+    /// Returns the paths of the files walked beneath `root`, relative to it.
+    fn walked(root: &Utf8Path, options: &WalkOptions) -> Vec<String> {
+        walk_dir(root, options)
+            .into_iter()
+            .map(|file| {
+                assert!(file.hashable.is_ok(), "{:?}", file.hashable);
+                file.file_path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .as_str()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sorted_by_path() {
+        let root = test_dir("sorted");
+        create_files(&root, &["b", "a/z", "a/b/c", "A", "a.txt"]);
+        assert_eq!(
+            walked(&root, &WalkOptions::default()),
+            ["A", "a/b/c", "a/z", "a.txt", "b"]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let root = test_dir("filters");
+        create_files(
+            &root,
+            &[
+                "a.rs",
+                "b.txt",
+                "target/c.rs",
+                "sub/d.rs",
+                "sub/target/e.rs",
+            ],
+        );
+        let options = |include: &[&str], exclude: &[&str]| WalkOptions {
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+            respect_gitignore: false,
+        };
+
+        assert_eq!(
+            walked(&root, &options(&["*.rs"], &[])),
+            ["a.rs", "sub/d.rs", "sub/target/e.rs", "target/c.rs"]
+        );
+        assert_eq!(
+            walked(&root, &options(&[], &["target/"])),
+            ["a.rs", "b.txt", "sub/d.rs"]
+        );
+        assert_eq!(
+            walked(&root, &options(&[], &["/target/"])),
+            ["a.rs", "b.txt", "sub/d.rs", "sub/target/e.rs"]
+        );
+        // Exclusions take precedence over inclusions.
+        assert_eq!(
+            walked(&root, &options(&["*.rs"], &["target/", "a.rs"])),
+            ["sub/d.rs"]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn respect_gitignore() {
+        let root = test_dir("gitignore");
+        create_files(&root, &["a", "b.log", "sub/c.log", "sub/d"]);
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("sub/.ignore"), "d\n").unwrap();
+
+        assert_eq!(
+            walked(&root, &WalkOptions::default()),
+            [
+                ".gitignore",
+                "a",
+                "b.log",
+                "sub/.ignore",
+                "sub/c.log",
+                "sub/d"
+            ]
+        );
+        let options = WalkOptions {
+            respect_gitignore: true,
+            ..WalkOptions::default()
+        };
+        assert_eq!(walked(&root, &options), [".gitignore", "a", "sub/.ignore"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn validate_globs() {
+        assert!(WalkOptions::default().validate().is_ok());
+        let valid = WalkOptions {
+            include: vec!["*.rs".to_string()],
+            exclude: vec!["/target/".to_string()],
+            ..WalkOptions::default()
+        };
+        assert!(valid.validate().is_ok());
+        let invalid = WalkOptions {
+            exclude: vec!["a[".to_string()],
+            ..WalkOptions::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}